@@ -0,0 +1,300 @@
+use std::{
+    fs,
+    fs::File,
+    io::Write,
+    mem::ManuallyDrop,
+    os::unix::io::FromRawFd,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::sandbox::Sandbox;
+use crate::status::{CompileStatus, Limits, ResultFormat, RunnerStatus, Verdict, RESULT_VERSION};
+
+/// 目录中可作为答案文件的扩展名，按顺序查找
+const ANSWER_EXTENSIONS: [&str; 2] = ["out", "ans"];
+
+/// 一个测试点
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub input: PathBuf,
+    /// 没有答案时只根据运行情况判定结果
+    pub answer: Option<PathBuf>,
+}
+
+/// 从目录或清单文件中读取测试点
+///
+/// - 目录：每个 `<name>.in` 为一个测试点，答案为同名的 `.out` 或 `.ans` 文件，纯数字的名称按数值排序
+/// - 清单文件：每行为 `输入 [答案]`，相对路径以清单所在的目录为基准，空行与 `#` 开头的行会被忽略
+pub fn load(path: &Path) -> Result<Vec<TestCase>> {
+    let cases = if try_io!(fs::metadata(path)).is_dir() {
+        load_dir(path)?
+    } else {
+        load_manifest(path)?
+    };
+    // 运行中途才发现文件不存在时，已经运行的测试点就白费了，因此提前检查
+    for case in &cases {
+        for file in std::iter::once(&case.input).chain(&case.answer) {
+            if !file.is_file() {
+                return Err(Error::TestCaseError(format!("{} does not exist", file.display())));
+            }
+        }
+    }
+    if cases.is_empty() {
+        warn!("no test case found in {}", path.display());
+    }
+    Ok(cases)
+}
+
+fn load_dir(dir: &Path) -> Result<Vec<TestCase>> {
+    let mut cases = vec![];
+    for entry in try_io!(fs::read_dir(dir)) {
+        let input = try_io!(entry).path();
+        if input.extension().and_then(|ext| ext.to_str()) != Some("in") {
+            continue;
+        }
+        let name = match input.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let answer = ANSWER_EXTENSIONS
+            .iter()
+            .map(|ext| input.with_extension(ext))
+            .find(|answer| answer.is_file());
+        cases.push(TestCase { name, input, answer });
+    }
+    cases.sort_by(|a, b| match (a.name.parse::<u64>(), b.name.parse::<u64>()) {
+        (Ok(x), Ok(y)) => x.cmp(&y),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.name.cmp(&b.name),
+    });
+    Ok(cases)
+}
+
+fn load_manifest(manifest: &Path) -> Result<Vec<TestCase>> {
+    let base = manifest.parent().unwrap_or_else(|| Path::new("."));
+    let mut cases = vec![];
+    for line in try_io!(fs::read_to_string(manifest)).lines() {
+        let mut files = line.split_whitespace();
+        let input = match files.next() {
+            Some(input) if !input.starts_with('#') => base.join(input),
+            _ => continue,
+        };
+        let name = input
+            .file_stem()
+            .map_or_else(|| (cases.len() + 1).to_string(), |stem| stem.to_string_lossy().to_string());
+        let answer = files.next().map(|answer| base.join(answer));
+        cases.push(TestCase { name, input, answer });
+    }
+    Ok(cases)
+}
+
+/// 单个测试点的运行结果
+#[derive(Debug, Serialize)]
+pub struct CaseStatus {
+    pub name: String,
+    #[serde(flatten)]
+    pub status: RunnerStatus,
+}
+
+/// 所有测试点的运行结果
+#[derive(Debug, Serialize)]
+pub struct BatchStatus {
+    /// 第一个未通过的测试点的结果，全部通过时为 Accepted
+    pub verdict: Verdict,
+    pub total: usize,
+    pub passed: usize,
+    /// 遇到未通过的测试点后停止时，剩余未运行的测试点数量
+    pub skipped: usize,
+    /// 各测试点中的最大值，单位同 RunnerStatus
+    pub time_used: i64,
+    pub memory_used: i64,
+    pub cases: Vec<CaseStatus>,
+    /// 先编译再运行时编译器的运行结果
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compile: Option<Box<CompileStatus>>,
+}
+
+/// 结构化输出的完整文档
+#[derive(Serialize)]
+struct BatchDocument<'a> {
+    version: u32,
+    #[serde(flatten)]
+    status: &'a BatchStatus,
+    limits: &'a Limits,
+}
+
+/// 依次运行所有测试点，每个测试点都使用 sandbox 新创建的沙盒，因此资源限制完全相同，创建失败时返回其错误
+///
+/// 测试点的输入与答案会覆盖沙盒原有的标准输入与答案设置，没有答案的测试点的输出会被捕获到其运行结果的 stdout 中，
+/// stop_on_failure 为 true 时在第一个未通过的测试点后停止
+pub fn run<F>(cases: &[TestCase], mut sandbox: F, stop_on_failure: bool) -> Result<BatchStatus>
+where
+    F: FnMut() -> Result<Sandbox>,
+{
+    let mut batch = BatchStatus {
+        verdict: Verdict::Accepted,
+        total: cases.len(),
+        passed: 0,
+        skipped: 0,
+        time_used: 0,
+        memory_used: 0,
+        cases: vec![],
+        compile: None,
+    };
+    for (i, case) in cases.iter().enumerate() {
+        let mut contestant = sandbox()?.stdin(case.input.to_string_lossy().to_string());
+        match &case.answer {
+            Some(answer) => contestant = contestant.answer(answer.to_string_lossy().to_string()),
+            // 不与结果混在一起输出
+            None => contestant.capture_stdout = true,
+        }
+        let status = contestant.judge()?;
+        debug!("case {}: {}", case.name, status.verdict);
+
+        batch.time_used = batch.time_used.max(status.time_used);
+        batch.memory_used = batch.memory_used.max(status.memory_used);
+        let passed = status.verdict == Verdict::Accepted;
+        if passed {
+            batch.passed += 1;
+        } else if batch.verdict == Verdict::Accepted {
+            batch.verdict = status.verdict;
+        }
+        batch.cases.push(CaseStatus {
+            name: case.name.clone(),
+            status,
+        });
+        if !passed && stop_on_failure {
+            batch.skipped = cases.len() - i - 1;
+            break;
+        }
+    }
+    Ok(batch)
+}
+
+impl BatchStatus {
+    /// 编译失败时的结果，所有测试点都没有运行
+    pub fn compile_error(cases: &[TestCase], compile: CompileStatus) -> Self {
+        BatchStatus {
+            verdict: Verdict::CompileError,
+            total: cases.len(),
+            passed: 0,
+            skipped: cases.len(),
+            time_used: 0,
+            memory_used: 0,
+            cases: vec![],
+            compile: Some(Box::new(compile)),
+        }
+    }
+
+    pub fn result_to_fd(&self, fd: i32, format: ResultFormat, limits: &Limits) -> Result<()> {
+        // 描述符由调用方管理，此处不能在 drop 时将其关闭
+        let mut f = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let document = BatchDocument {
+            version: RESULT_VERSION,
+            status: self,
+            limits,
+        };
+        match format {
+            ResultFormat::Text => self.result_to_text(&mut f),
+            ResultFormat::Json => {
+                let json = match serde_json::to_string_pretty(&document) {
+                    Ok(json) => json,
+                    Err(e) => return Err(Error::SerializeError(e.to_string())),
+                };
+                try_io!(writeln!(&mut *f, "{}", json));
+                Ok(())
+            }
+            ResultFormat::Toml => {
+                let toml = match toml::to_string(&document) {
+                    Ok(toml) => toml,
+                    Err(e) => return Err(Error::SerializeError(e.to_string())),
+                };
+                try_io!(write!(&mut *f, "{}", toml));
+                Ok(())
+            }
+        }
+    }
+
+    /// 先输出汇总结果，再依次输出每个测试点的结果，测试点之间以 `case = <name>` 分隔
+    fn result_to_text(&self, f: &mut File) -> Result<()> {
+        try_io!(write!(f,
+"verdict = {}
+total = {}
+passed = {}
+skipped = {}
+time_used = {}
+memory_used = {}
+", self.verdict, self.total, self.passed, self.skipped, self.time_used, self.memory_used
+        ));
+        if let Some(compile) = &self.compile {
+            compile.result_to_text(f)?;
+        }
+        for case in &self.cases {
+            try_io!(writeln!(f, "\ncase = {}", case.name));
+            case.status.result_to_text(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(cases: &[TestCase]) -> Vec<&str> {
+        cases.iter().map(|case| case.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dir_sorted_numerically() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &["10.in", "2.in", "1.in", "b.in", "a.in", "1.out", "2.ans", "a.txt", "c.out"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let cases = load(dir.path()).unwrap();
+        assert_eq!(names(&cases), ["1", "2", "10", "a", "b"]);
+        assert_eq!(cases[0].answer, Some(dir.path().join("1.out")));
+        assert_eq!(cases[1].answer, Some(dir.path().join("2.ans")));
+        assert_eq!(cases[2].answer, None);
+    }
+
+    #[test]
+    fn dir_prefers_out_over_ans() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &["1.in", "1.out", "1.ans"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let cases = load(dir.path()).unwrap();
+        assert_eq!(cases[0].answer, Some(dir.path().join("1.out")));
+    }
+
+    #[test]
+    fn manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("data")).unwrap();
+        for name in &["data/big.in", "data/big.ans", "small.in"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let manifest = dir.path().join("tests.txt");
+        fs::write(&manifest, "# comment\n\ndata/big.in  data/big.ans\n  small.in\n").unwrap();
+        let cases = load(&manifest).unwrap();
+        assert_eq!(names(&cases), ["big", "small"]);
+        assert_eq!(cases[0].input, dir.path().join("data/big.in"));
+        assert_eq!(cases[0].answer, Some(dir.path().join("data/big.ans")));
+        assert_eq!(cases[1].answer, None);
+    }
+
+    #[test]
+    fn manifest_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("1.in"), "").unwrap();
+        let manifest = dir.path().join("tests.txt");
+        fs::write(&manifest, "1.in 1.out\n").unwrap();
+        assert!(matches!(load(&manifest), Err(Error::TestCaseError(_))));
+    }
+}
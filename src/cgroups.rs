@@ -1,19 +1,161 @@
+use std::convert::TryInto;
 use std::fs;
 use std::fs::{read_to_string, remove_dir};
-use std::path::PathBuf;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use libc;
-use tempfile::tempdir_in;
+use tempfile::Builder;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 pub struct CGroupOptions {
+    /// cgroup 版本，0 表示根据 /sys/fs/cgroup 自动检测
     pub version: i32,
+    /// 沙盒控制组的上级控制组，为相对于层级根目录的路径，None 表示当前进程所在的控制组
+    pub parent: Option<String>,
     /// 允许通过 fork 与 clone 产生的最大进程数量
     pub pids: i32,
+    /// 允许使用的最大内存（不含 swap），单位 byte
+    pub memory: i64,
+    /// 每个 CPU_PERIOD 周期内允许使用的 CPU 时间，单位 us
+    pub cpu_quota: i64,
+    /// 允许使用的 CPU 列表，如 `0-3,5`
+    pub cpuset_cpus: Option<String>,
+    /// 允许使用的内存节点列表，如 `0`
+    pub cpuset_mems: Option<String>,
+    /// I/O 限制，不限制时为 None
+    pub io: Option<IoLimit>,
 }
 
-impl CGroupOptions {}
+/// 针对单个块设备的 I/O 限制，各项为 0 时表示不限制
+pub struct IoLimit {
+    /// 块设备号 `(major, minor)`
+    pub device: (u32, u32),
+    pub read_bps: u64,
+    pub write_bps: u64,
+    pub read_iops: u64,
+    pub write_iops: u64,
+}
+
+/// CPU 带宽限制的统计周期，单位 us
+pub const CPU_PERIOD: i64 = 100_000;
+
+impl CGroupOptions {
+    /// 实现所设置的限制必须用到的 controller，以 cgroup v1 的名称表示
+    ///
+    /// cpuacct 与 blkio 的统计功能在 controller 缺失时会被跳过，因此不在此列
+    fn required_controllers(&self) -> Vec<&'static str> {
+        let mut controllers = vec![];
+        if self.pids > 0 {
+            controllers.push("pids");
+        }
+        if self.memory > 0 {
+            controllers.push("memory");
+        }
+        if self.cpu_quota > 0 {
+            controllers.push("cpu");
+        }
+        if self.cpuset_cpus.is_some() || self.cpuset_mems.is_some() {
+            controllers.push("cpuset");
+        }
+        if self.io.is_some() {
+            controllers.push("blkio");
+        }
+        controllers
+    }
+}
+
+/// 上级控制组的完整路径，parent 为 None 时使用当前进程所在的控制组，controller 为 None 时表示 cgroup v2
+fn parent_path(parent: Option<&str>, root: &Path, controller: Option<&str>) -> Result<PathBuf> {
+    let relative = match parent {
+        Some(parent) => PathBuf::from(parent),
+        None => match self_cgroup(controller) {
+            // 沙盒自身已被移动到 supervisor 中，沙盒控制组仍然创建在原来的控制组下
+            Some(path) if controller.is_none() && path.ends_with(SUPERVISOR_CGROUP) => {
+                path.parent().unwrap().to_path_buf()
+            }
+            Some(path) => path,
+            None => {
+                return Err(Error::CGroupError(format!(
+                    "cannot find the cgroup of the current process for {}",
+                    controller.unwrap_or("cgroup v2")
+                )))
+            }
+        },
+    };
+    // 相对路径中的 .. 可能逃逸出层级根目录
+    if relative.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::CGroupError(format!("invalid cgroup parent {:?}", relative)));
+    }
+    Ok(join_root(root, &relative))
+}
+
+/// 将 /proc/self/cgroup 中以 / 开头的路径拼接到层级根目录下
+fn join_root(root: &Path, relative: &Path) -> PathBuf {
+    root.join(relative.strip_prefix("/").unwrap_or(relative))
+}
+
+/// 从 /proc/self/cgroup 中读取当前进程所在的控制组
+///
+/// 每行格式为 `hierarchy-ID:controller-list:path`，cgroup v2 的 controller-list 为空
+fn self_cgroup(controller: Option<&str>) -> Option<PathBuf> {
+    read_to_string("/proc/self/cgroup")
+        .ok()?
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            Some((fields.next()?, fields.next()?, fields.next()?))
+        })
+        .find(|(_, controllers, _)| match controller {
+            Some(controller) => controllers.split(',').any(|c| c == controller),
+            None => controllers.is_empty(),
+        })
+        .map(|(_, _, path)| PathBuf::from(path))
+}
+
+/// statfs(2) 中各文件系统的 magic number，libc 中没有提供
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+const CGROUP_SUPER_MAGIC: i64 = 0x0027_e0eb;
+const CGROUP2_SUPER_MAGIC: i64 = 0x6367_7270;
+
+/// 挂载在 path 上的文件系统类型，path 不存在时返回 None
+fn fs_type(path: &Path) -> Option<i64> {
+    let path = std::ffi::CString::new(path.to_str()?).ok()?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } < 0 {
+        return None;
+    }
+    Some(buf.f_type as i64)
+}
+
+/// cgroup v1 中 controller 是否已挂载
+fn v1_mounted(controller: &str) -> bool {
+    fs_type(&Path::new(CGROUP_ROOT).join(controller)) == Some(CGROUP_SUPER_MAGIC)
+}
+
+/// 通过 /sys/fs/cgroup 的文件系统类型检测当前主机使用的 cgroup 版本
+///
+/// - cgroup2：纯 cgroup v2（unified）
+/// - tmpfs：cgroup v1 或 hybrid，各 controller 分别挂载在子目录中，hybrid 下的 unified 目录不含任何 controller，因此同样使用 v1
+pub fn detect_version() -> Result<i32> {
+    match fs_type(Path::new(CGROUP_ROOT)) {
+        Some(CGROUP2_SUPER_MAGIC) => Ok(2),
+        Some(TMPFS_MAGIC) if V1_CONTROLLERS.iter().any(|c| v1_mounted(c)) => Ok(1),
+        _ => Err(Error::CGroupError(format!("no cgroup hierarchy is mounted at {}", CGROUP_ROOT))),
+    }
+}
+
+/// 运行结束后从控制组中读取到的事件计数
+#[derive(Debug, Default)]
+pub struct CGroupEvents {
+    /// 因达到 pids.max 而失败的 fork 与 clone 次数
+    pub pids_max: u64,
+    /// 因超出内存限制而被 OOM killer 结束的进程数量
+    pub oom_kill: u64,
+}
 
 #[allow(dead_code)]
 pub struct CGroup {
@@ -22,49 +164,549 @@ pub struct CGroup {
 }
 
 impl CGroup {
-    pub fn apply(options: CGroupOptions) -> Result<Self> {
-        let pid = unsafe { libc::getpid() };
+    /// 创建控制组并写入资源限制，此时控制组中还没有任何进程
+    pub fn apply(mut options: CGroupOptions) -> Result<Self> {
+        if options.version == 0 {
+            options.version = detect_version()?;
+            debug!("detected cgroup version = {}", options.version);
+        }
         let mut v1 = None;
         let mut v2 = None;
-        if options.version == 1 {
-            v1 = Option::from(CGroupV1::apply(pid, options)?);
-        } else if options.version == 2 {
-            v2 = Option::from(CGroupV2::apply(pid, options)?);
+        match options.version {
+            1 => v1 = Some(CGroupV1::apply(options)?),
+            2 => v2 = Some(CGroupV2::apply(options)?),
+            version => return Err(Error::CGroupError(format!("unsupported cgroup version {}", version))),
         }
         Ok(CGroup {
             v1,
             v2,
         })
     }
+
+    /// 将当前进程加入控制组，由沙盒内部 pid = 1 的进程在创建子进程前调用
+    ///
+    /// 向 cgroup.procs 写入 0 表示写入者自身，因此无需知道当前进程在外部 pid namespace 中的 pid
+    pub fn join(&self) -> Result<()> {
+        if let Some(v1) = &self.v1 {
+            for path in v1.paths() {
+                try_io!(fs::write(path.join("cgroup.procs"), "0"));
+            }
+        }
+        if let Some(v2) = &self.v2 {
+            try_io!(fs::write(v2.path.join("cgroup.procs"), "0"));
+        }
+        Ok(())
+    }
+
+    /// 结束控制组内的所有进程并删除控制组
+    ///
+    /// drop 时也会执行同样的操作，但只能将错误记录到日志中
+    pub fn destroy(mut self) -> Result<()> {
+        if let Some(v1) = &mut self.v1 {
+            v1.teardown()?;
+        }
+        if let Some(v2) = &mut self.v2 {
+            v2.teardown()?;
+        }
+        Ok(())
+    }
+
+    pub fn events(&self) -> CGroupEvents {
+        let mut events = CGroupEvents::default();
+        if let Some(path) = self.controller_path("pids") {
+            events.pids_max = read_event(&path.join("pids.events"), "max");
+        }
+        if let Some(path) = self.controller_path("memory") {
+            events.oom_kill = if self.v1.is_some() {
+                read_event(&path.join("memory.oom_control"), "oom_kill")
+            } else {
+                read_event(&path.join("memory.events"), "oom_kill")
+            };
+        }
+        events
+    }
+
+    /// 控制组内所有进程消耗的 CPU 时间总和，单位 us
+    pub fn cpu_usage(&self) -> Option<i64> {
+        if self.v1.is_some() {
+            let path = self.controller_path("cpuacct")?;
+            let usage: i64 = read_to_string(path.join("cpuacct.usage")).ok()?.trim().parse().ok()?;
+            Some(usage / 1000)
+        } else {
+            let path = self.controller_path("cpu")?;
+            read_key(&path.join("cpu.stat"), "usage_usec")?.try_into().ok()
+        }
+    }
+
+    /// 控制组内所有进程读取与写入块设备的总字节数
+    pub fn io_bytes(&self) -> Option<(u64, u64)> {
+        if self.v1.is_some() {
+            let path = self.controller_path("blkio")?;
+            Some(blkio_bytes(&read_to_string(path.join("blkio.throttle.io_service_bytes")).ok()?))
+        } else {
+            let path = self.controller_path("io")?;
+            Some(io_stat_bytes(&read_to_string(path.join("io.stat")).ok()?))
+        }
+    }
+
+    /// 控制组内所有进程的内存使用峰值，单位 byte
+    ///
+    /// cgroup v2 的 memory.peak 需要 5.19 以上的内核，不存在时返回 None
+    pub fn memory_peak(&self) -> Option<i64> {
+        let path = self.controller_path("memory")?;
+        let file = if self.v1.is_some() { "memory.max_usage_in_bytes" } else { "memory.peak" };
+        read_to_string(path.join(file)).ok()?.trim().parse().ok()
+    }
+
+    /// 记录控制组内所有进程的 cgroup.procs 文件
+    pub fn procs(&self) -> Option<PathBuf> {
+        match (&self.v1, &self.v2) {
+            (Some(v1), _) => v1.paths().first().map(|path| path.join("cgroup.procs")),
+            (_, Some(v2)) => Some(v2.path.join("cgroup.procs")),
+            _ => None,
+        }
+    }
+
+    /// 实际使用的 cgroup 版本
+    pub fn version(&self) -> i32 {
+        if self.v1.is_some() { 1 } else { 2 }
+    }
+
+    fn controller_path(&self, controller: &str) -> Option<&Path> {
+        match (&self.v1, &self.v2) {
+            (Some(v1), _) => v1.path(controller),
+            (_, Some(v2)) => Some(&v2.path),
+            _ => None,
+        }
+    }
+}
+
+/// 读取 `*.events` 格式（每行 `key value`）文件中的指定计数，读取失败时视为 0
+fn read_event(path: &Path, key: &str) -> u64 {
+    read_key(path, key).unwrap_or(0)
+}
+
+/// 读取 `key value` 格式（如 `*.events`、`cpu.stat`）文件中的指定数值
+fn read_key(path: &Path, key: &str) -> Option<u64> {
+    parse_key(&read_to_string(path).ok()?, key)
+}
+
+fn parse_key(content: &str, key: &str) -> Option<u64> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.trim().parse().ok())
+}
+
+/// 汇总 cgroup v1 的 blkio.throttle.io_service_bytes 中所有设备的读写字节数
+///
+/// 每行格式为 `8:0 Read 4096`，最后一行为 `Total N`
+fn blkio_bytes(content: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut write = 0;
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "Read", n] => read += n.parse::<u64>().unwrap_or(0),
+            [_, "Write", n] => write += n.parse::<u64>().unwrap_or(0),
+            _ => {}
+        }
+    }
+    (read, write)
+}
+
+/// 汇总 cgroup v2 的 io.stat 中所有设备的读写字节数
+///
+/// 每行格式为 `8:0 rbytes=4096 wbytes=0 rios=1 wios=0 dbytes=0 dios=0`
+fn io_stat_bytes(content: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut write = 0;
+    for field in content.split_whitespace() {
+        match field.split_once('=') {
+            Some(("rbytes", n)) => read += n.parse::<u64>().unwrap_or(0),
+            Some(("wbytes", n)) => write += n.parse::<u64>().unwrap_or(0),
+            _ => {}
+        }
+    }
+    (read, write)
+}
+
+/// 仅在控制文件存在时写入，用于内核未开启的可选功能（如 swap 统计）
+fn write_if_exists(path: PathBuf, content: &str) -> Result<()> {
+    if path.exists() {
+        try_io!(fs::write(path, content));
+    }
+    Ok(())
+}
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// 销毁控制组时等待进程退出、冻结完成与目录删除的最大重试次数及每次的间隔
+const TEARDOWN_RETRIES: u32 = 100;
+const TEARDOWN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 向控制组中的所有进程发送 SIGKILL，返回发送的进程数量
+fn signal_all(path: &Path) -> Result<usize> {
+    let procs = try_io!(read_to_string(path.join("cgroup.procs")));
+    let mut count = 0;
+    for pid in procs.lines().filter_map(|pid| pid.trim().parse::<i32>().ok()) {
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 不断 kill 控制组中的进程，直到控制组为空
+///
+/// 未被冻结的进程可能在两次读取之间 fork 出新的进程，因此需要重复读取
+fn kill_all(path: &Path) -> Result<()> {
+    for _ in 0..TEARDOWN_RETRIES {
+        if signal_all(path)? == 0 {
+            return Ok(());
+        }
+        thread::sleep(TEARDOWN_INTERVAL);
+    }
+    Err(Error::CGroupError(format!("processes in {:?} are still alive after SIGKILL", path)))
+}
+
+/// 删除空的控制组目录，进程刚退出时内核可能尚未完成清理，此时返回 EBUSY 需要重试
+fn remove_cgroup(path: &Path) -> Result<()> {
+    for _ in 0..TEARDOWN_RETRIES {
+        match remove_dir(path) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => thread::sleep(TEARDOWN_INTERVAL),
+            Err(e) => return Err(Error::IOError(e)),
+        }
+    }
+    Err(Error::CGroupError(format!("cannot remove {:?}, it is still busy", path)))
+}
+
+/// 沙盒控制组的目录名前缀，完整格式为 `newbie-sandbox-<pid namespace>-<pid>-<启动时间>-<随机字符>`
+///
+/// 名称中记录了创建者所在的 pid namespace、pid 与启动时间，用于判断控制组是否已被遗弃
+pub const CGROUP_PREFIX: &str = "newbie-sandbox-";
+
+/// 当前进程所在 pid namespace 的 inode 号
+fn pid_namespace() -> Option<u64> {
+    let metadata = fs::metadata("/proc/self/ns/pid").ok()?;
+    Some(std::os::unix::fs::MetadataExt::ino(&metadata))
+}
+
+/// 在 parent 下以带前缀的名称创建新的控制组目录
+fn create_cgroup(parent: &Path) -> Result<PathBuf> {
+    let start_time = match process_stat("self") {
+        Some((_, start_time)) => start_time,
+        None => return Err(Error::CGroupError("cannot read the start time of the process".to_string())),
+    };
+    let prefix = format!(
+        "{}{}-{}-{}-",
+        CGROUP_PREFIX,
+        pid_namespace().unwrap_or(0),
+        std::process::id(),
+        start_time
+    );
+    Ok(try_io!(Builder::new().prefix(&prefix).tempdir_in(parent)).into_path())
+}
+
+/// 控制组是否由已经退出的沙盒创建
+///
+/// 创建者与当前进程不在同一个 pid namespace 时无法判断其是否存活，此时视为仍在使用
+fn is_orphan(name: &str) -> bool {
+    let mut fields = match name.strip_prefix(CGROUP_PREFIX) {
+        Some(rest) => rest.split('-'),
+        None => return false,
+    };
+    let namespace = fields.next().and_then(|ns| ns.parse::<u64>().ok());
+    let pid = fields.next().and_then(|pid| pid.parse::<u32>().ok());
+    let start_time = fields.next().and_then(|start_time| start_time.parse::<u64>().ok());
+    let (namespace, pid, start_time) = match (namespace, pid, start_time) {
+        (Some(namespace), Some(pid), Some(start_time)) => (namespace, pid, start_time),
+        _ => return false,
+    };
+    if Some(namespace) != pid_namespace() || pid == std::process::id() {
+        return false;
+    }
+    // pid 可能已被其他进程复用，因此还需要比较启动时间，已退出但尚未被回收的进程同样视为不存在
+    match process_stat(&pid.to_string()) {
+        Some((state, current)) => current != start_time || state == 'Z',
+        None => true,
+    }
+}
+
+/// 从 /proc/<pid>/stat 中读取进程的状态与启动时间
+fn process_stat(pid: &str) -> Option<(char, u64)> {
+    parse_stat(&read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+/// 解析 /proc/<pid>/stat 的内容，格式为 `pid (comm) state ...`，启动时间为第 22 个字段
+fn parse_stat(stat: &str) -> Option<(char, u64)> {
+    // 程序名中可能包含括号与空格，因此从最后一个右括号之后的第 3 个字段开始解析
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let start_time = fields.nth(18)?.parse().ok()?;
+    Some((state, start_time))
+}
+
+/// kill 控制组中的所有进程并删除控制组目录，可用于任意版本的控制组
+///
+/// 优先冻结控制组，避免 fork 炸弹在 kill 的过程中继续产生新的进程：
+/// - cgroup v1 的 freezer 中被冻结的进程在解冻前不会处理 SIGKILL，因此需要先发送信号再解冻
+/// - cgroup v2 优先使用 5.14 起提供的 cgroup.kill，否则通过 cgroup.freeze 冻结，被冻结的进程同样会处理 SIGKILL
+fn destroy_cgroup(path: &Path) -> Result<()> {
+    let freezer_state = path.join("freezer.state");
+    let kill = path.join("cgroup.kill");
+    let freeze = path.join("cgroup.freeze");
+    if freezer_state.exists() {
+        try_io!(fs::write(&freezer_state, "FROZEN"));
+        if !wait_for(&freezer_state, |state| state.trim() == "FROZEN") {
+            warn!("cgroup {:?} is not frozen in time", path);
+        }
+        signal_all(path)?;
+        try_io!(fs::write(&freezer_state, "THAWED"));
+    } else if kill.exists() {
+        try_io!(fs::write(kill, "1"));
+    } else if freeze.exists() {
+        try_io!(fs::write(freeze, "1"));
+        if !wait_for(&path.join("cgroup.events"), |events| events.lines().any(|l| l == "frozen 1")) {
+            warn!("cgroup {:?} is not frozen in time", path);
+        }
+        signal_all(path)?;
+    }
+    kill_all(path)?;
+    remove_cgroup(path)
+}
+
+/// 清理 dir 下所有已被遗弃的沙盒控制组，recursive 为 true 时同时检查所有子孙控制组，返回清理的数量
+///
+/// 清理失败时只记录日志，不影响其他控制组的清理
+fn sweep(dir: &Path, recursive: bool) -> usize {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut count = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        // 不跟随符号链接，避免重复访问 cpu,cpuacct 这类共享的层级
+        if !matches!(entry.file_type(), Ok(t) if t.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        let name = entry.file_name();
+        if is_orphan(&name.to_string_lossy()) {
+            match destroy_cgroup(&path) {
+                Ok(()) => {
+                    info!("removed orphaned cgroup {:?}", path);
+                    count += 1;
+                }
+                Err(e) => error!("failed to remove orphaned cgroup {:?}: {}", path, e),
+            }
+        } else if recursive {
+            count += sweep(&path, true);
+        }
+    }
+    count
+}
+
+/// 清理整个 cgroup 层级中所有已被遗弃的沙盒控制组，返回清理的数量
+///
+/// version 为 0 时自动检测
+pub fn gc(version: i32) -> Result<usize> {
+    let version = if version == 0 { detect_version()? } else { version };
+    let mut roots: Vec<PathBuf> = vec![];
+    if version == 1 {
+        for controller in V1_CONTROLLERS.iter().filter(|c| v1_mounted(c)) {
+            let root = try_io!(fs::canonicalize(Path::new(CGROUP_ROOT).join(controller)));
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+    } else {
+        roots.push(PathBuf::from(CGROUP_ROOT));
+    }
+    Ok(roots.iter().map(|root| sweep(root, true)).sum())
+}
+
+/// 轮询直到 file 的内容满足 done，超时后返回 false
+fn wait_for(file: &Path, done: impl Fn(&str) -> bool) -> bool {
+    for _ in 0..TEARDOWN_RETRIES {
+        if matches!(read_to_string(file), Ok(content) if done(&content)) {
+            return true;
+        }
+        thread::sleep(TEARDOWN_INTERVAL);
+    }
+    false
 }
 
+/// 沙盒可能用到的所有 cgroup v1 controller
+const V1_CONTROLLERS: [&str; 7] = ["pids", "memory", "cpu", "cpuacct", "cpuset", "blkio", "freezer"];
+
 pub struct CGroupV1 {
-    pids_path: Option<PathBuf>,
+    /// 每个 controller 对应的控制组目录，挂载在同一层级（如 cpu,cpuacct）的 controller 共用同一个目录
+    paths: Vec<(&'static str, PathBuf)>,
+    /// 同 CGroupOptions::parent
+    parent: Option<String>,
 }
 
 impl CGroupV1 {
-    pub fn apply(pid: i32, options: CGroupOptions) -> Result<Self> {
-        let mut pids_path = None;
+    pub fn apply(options: CGroupOptions) -> Result<Self> {
+        let missing: Vec<&str> = options
+            .required_controllers()
+            .into_iter()
+            .filter(|c| !v1_mounted(c))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::CGroupError(format!(
+                "cgroup v1 controllers not mounted under {}: {}",
+                CGROUP_ROOT,
+                missing.join(", ")
+            )));
+        }
+        let mut cgroup = CGroupV1 {
+            paths: vec![],
+            parent: options.parent.clone(),
+        };
+        // 始终统计 CPU 时间，wait4 无法统计到未被回收的孙进程与分离出去的线程
+        if v1_mounted("cpuacct") {
+            cgroup.create("cpuacct")?;
+        }
+        // 用于销毁时冻结所有进程，避免 fork 炸弹在 kill 的过程中继续产生新的进程
+        if v1_mounted("freezer") {
+            cgroup.create("freezer")?;
+        }
         if options.pids > 0 {
-            let pwd = try_io!(tempdir_in("/sys/fs/cgroup/pids"));
-            pids_path = Some(pwd.path().to_path_buf());
-            trace!("cgroup v1 pids path = {:?}", pwd.path());
-            try_io!(fs::write(pwd.path().join("cgroup.procs"), format!("{}", pid)));
-            try_io!(fs::write(pwd.path().join("pids.max"), format!("{}", options.pids)));
+            let path = cgroup.create("pids")?;
+            try_io!(fs::write(path.join("pids.max"), format!("{}", options.pids)));
+        }
+        if options.memory > 0 {
+            let path = cgroup.create("memory")?;
+            try_io!(fs::write(path.join("memory.limit_in_bytes"), format!("{}", options.memory)));
+            // 禁用 swap，内存与 swap 之和的限制必须在内存限制之后设置
+            write_if_exists(path.join("memory.memsw.limit_in_bytes"), &format!("{}", options.memory))?;
+            write_if_exists(path.join("memory.swappiness"), "0")?;
         }
+        if options.cpu_quota > 0 {
+            let path = cgroup.create("cpu")?;
+            try_io!(fs::write(path.join("cpu.cfs_period_us"), format!("{}", CPU_PERIOD)));
+            try_io!(fs::write(path.join("cpu.cfs_quota_us"), format!("{}", options.cpu_quota)));
+        }
+        if options.cpuset_cpus.is_some() || options.cpuset_mems.is_some() {
+            let path = cgroup.create("cpuset")?;
+            // cgroup v1 中新建的 cpuset 为空，必须同时设置 cpus 与 mems 才能加入进程，未指定的项沿用上级控制组
+            let parent = path.parent().unwrap();
+            let cpus = match options.cpuset_cpus {
+                Some(cpus) => cpus,
+                None => try_io!(read_to_string(parent.join("cpuset.cpus"))),
+            };
+            let mems = match options.cpuset_mems {
+                Some(mems) => mems,
+                None => try_io!(read_to_string(parent.join("cpuset.mems"))),
+            };
+            try_io!(fs::write(path.join("cpuset.cpus"), cpus.trim()));
+            try_io!(fs::write(path.join("cpuset.mems"), mems.trim()));
+        }
+        // 始终统计读写量，限制只作用于指定的块设备
+        if !v1_mounted("blkio") {
+            return Ok(cgroup);
+        }
+        let path = cgroup.create("blkio")?;
+        if let Some(io) = options.io {
+            let (major, minor) = io.device;
+            let limits = [
+                ("blkio.throttle.read_bps_device", io.read_bps),
+                ("blkio.throttle.write_bps_device", io.write_bps),
+                ("blkio.throttle.read_iops_device", io.read_iops),
+                ("blkio.throttle.write_iops_device", io.write_iops),
+            ];
+            for (file, limit) in limits.iter() {
+                if *limit > 0 {
+                    try_io!(fs::write(path.join(file), format!("{}:{} {}", major, minor, limit)));
+                }
+            }
+        }
+        Ok(cgroup)
+    }
 
-        Ok(CGroupV1 {
-            pids_path
-        })
+    /// 在指定 controller 层级的上级控制组中创建控制组目录
+    fn create(&mut self, controller: &'static str) -> Result<PathBuf> {
+        let root = try_io!(fs::canonicalize(Path::new(CGROUP_ROOT).join(controller)));
+        let parent = parent_path(self.parent.as_deref(), &root, Some(controller))?;
+        let exists = self.paths.iter().find(|(_, path)| path.parent() == Some(&parent));
+        let path = match exists {
+            Some((_, path)) => path.clone(),
+            None => {
+                create_v1_parent(&root, &parent, controller)?;
+                sweep(&parent, false);
+                create_cgroup(&parent)?
+            }
+        };
+        trace!("cgroup v1 {} path = {:?}", controller, path);
+        self.paths.push((controller, path.clone()));
+        Ok(path)
+    }
+
+    fn path(&self, controller: &str) -> Option<&Path> {
+        self.paths
+            .iter()
+            .find(|(name, _)| *name == controller)
+            .map(|(_, path)| path.as_path())
+    }
+
+    /// 去重后的所有控制组目录
+    fn paths(&self) -> Vec<&PathBuf> {
+        let mut paths: Vec<&PathBuf> = vec![];
+        for (_, path) in &self.paths {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+}
+
+/// 逐级创建 cgroup v1 中不存在的上级控制组
+///
+/// 新建的 cpuset 为空，需要从上一级复制 cpus 与 mems 后才能在其中继续创建控制组
+fn create_v1_parent(root: &Path, parent: &Path, controller: &str) -> Result<()> {
+    let mut path = root.to_path_buf();
+    for component in parent.strip_prefix(root).unwrap().components() {
+        let upper = path.clone();
+        path.push(component);
+        if path.exists() {
+            continue;
+        }
+        try_io!(fs::create_dir(&path));
+        if controller == "cpuset" {
+            for file in ["cpuset.cpus", "cpuset.mems"].iter() {
+                let content = try_io!(read_to_string(upper.join(file)));
+                try_io!(fs::write(path.join(file), content.trim()));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl CGroupV1 {
+    /// 冻结控制组，kill 其中的所有进程，等待控制组为空后删除所有目录
+    fn teardown(&mut self) -> Result<()> {
+        let mut paths: Vec<PathBuf> = self.paths().into_iter().cloned().collect();
+        self.paths.clear();
+        // 其他层级中的进程与 freezer 中的相同，因此先处理 freezer
+        paths.sort_by_key(|path| !path.join("freezer.state").exists());
+        for path in &paths {
+            destroy_cgroup(path)?;
+        }
+        Ok(())
     }
 }
 
 impl Drop for CGroupV1 {
     fn drop(&mut self) {
-        if let Some(path) = &self.pids_path {
-            let pids = read_to_string(path.join("cgroup.procs")).unwrap();
-            fs::write("/sys/fs/cgroup/pids/cgroup.procs", pids).unwrap();
-            remove_dir(path).unwrap();
+        if let Err(e) = self.teardown() {
+            error!("failed to destroy cgroup: {}", e);
         }
     }
 }
@@ -75,28 +717,293 @@ pub struct CGroupV2 {
 }
 
 impl CGroupV2 {
-    pub fn apply(pid: i32, options: CGroupOptions) -> Result<Self> {
-        // 新建 cgroup v2 目录
-        let pwd = try_io!(tempdir_in("/sys/fs/cgroup"));
-        trace!("cgroup v2 path = {:?}", pwd.path());
-        // 将指定进程加入 cgroup 组里
-        try_io!(fs::write(pwd.path().join("cgroup.procs"), format!("{}", pid)));
+    pub fn apply(options: CGroupOptions) -> Result<Self> {
+        let root = Path::new(CGROUP_ROOT);
+        if fs_type(root) != Some(CGROUP2_SUPER_MAGIC) {
+            return Err(Error::CGroupError(format!("{} is not a cgroup v2 hierarchy", CGROUP_ROOT)));
+        }
+        let parent = parent_path(options.parent.as_deref(), root, None)?;
+        // 沙盒需要的 controller 与可选的统计用 controller
+        let mut controllers: Vec<&str> = options
+            .required_controllers()
+            .into_iter()
+            .map(|c| if c == "blkio" { "io" } else { c })
+            .collect();
+        for c in ["memory", "io"].iter() {
+            if !controllers.contains(c) {
+                controllers.push(c);
+            }
+        }
+        prepare_v2_parent(root, &parent, options.parent.is_some(), &controllers)?;
+
+        // 清理之前因沙盒被 kill 而遗留的控制组，再新建 cgroup v2 目录
+        sweep(&parent, false);
+        let path = create_cgroup(&parent)?;
+        trace!("cgroup v2 path = {:?}", path);
+        let cgroup = CGroupV2 { path };
+        // 子控制组可用的 controller 由上级的 cgroup.subtree_control 决定
+        let enabled = try_io!(read_to_string(cgroup.path.join("cgroup.controllers")));
+        let missing: Vec<&str> = options
+            .required_controllers()
+            .into_iter()
+            .map(|c| if c == "blkio" { "io" } else { c })
+            .filter(|c| !enabled.split_whitespace().any(|e| e == *c))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::CGroupError(format!(
+                "cgroup v2 controllers not enabled in {:?}: {}",
+                parent.join("cgroup.subtree_control"),
+                missing.join(", ")
+            )));
+        }
+        let path = &cgroup.path;
 
         if options.pids > 0 {
-            try_io!(fs::write(pwd.path().join("pids.max"), format!("{}", options.pids)));
+            try_io!(fs::write(path.join("pids.max"), format!("{}", options.pids)));
+        }
+        if options.memory > 0 {
+            try_io!(fs::write(path.join("memory.max"), format!("{}", options.memory)));
+            // 禁用 swap
+            write_if_exists(path.join("memory.swap.max"), "0")?;
+        }
+        if options.cpu_quota > 0 {
+            try_io!(fs::write(path.join("cpu.max"), format!("{} {}", options.cpu_quota, CPU_PERIOD)));
+        }
+        // cgroup v2 中为空时表示沿用上级控制组
+        if let Some(cpus) = options.cpuset_cpus {
+            try_io!(fs::write(path.join("cpuset.cpus"), cpus));
+        }
+        if let Some(mems) = options.cpuset_mems {
+            try_io!(fs::write(path.join("cpuset.mems"), mems));
+        }
+        if let Some(io) = options.io {
+            let (major, minor) = io.device;
+            let mut max = format!("{}:{}", major, minor);
+            let limits = [
+                ("rbps", io.read_bps),
+                ("wbps", io.write_bps),
+                ("riops", io.read_iops),
+                ("wiops", io.write_iops),
+            ];
+            for (key, limit) in limits.iter() {
+                if *limit > 0 {
+                    max.push_str(&format!(" {}={}", key, limit));
+                }
+            }
+            try_io!(fs::write(path.join("io.max"), max));
         }
 
-        Ok(CGroupV2 {
-            path: pwd.path().to_path_buf()
-        })
+        Ok(cgroup)
+    }
+}
+
+/// 名为 supervisor 的叶子控制组，用于存放原本位于上级控制组中的沙盒自身
+const SUPERVISOR_CGROUP: &str = "supervisor";
+
+/// 无法启用 controller 时的提示
+const DELEGATE_HINT: &str =
+    "run inside a delegated scope (e.g. systemd-run --scope -p Delegate=yes) or use --cgroup-parent";
+
+/// systemd 为 Delegate=yes 的 unit 设置的扩展属性，分别对应系统与用户的 service manager
+const DELEGATE_XATTRS: [&str; 2] = ["trusted.delegate", "user.delegate"];
+
+/// 控制组是否已委派给当前进程管理
+fn delegated(path: &Path) -> bool {
+    let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    DELEGATE_XATTRS.iter().any(|name| {
+        let name = std::ffi::CString::new(*name).unwrap();
+        unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) >= 0 }
+    })
+}
+
+/// 准备上级控制组，并在其 cgroup.subtree_control 中启用 controller
+///
+/// cgroup v2 不允许非根控制组在启用 controller 的同时包含进程，因此沙盒自身位于上级控制组中时（如 systemd
+/// 中 Delegate=yes 的 service 或 scope），需要先将自身移动到叶子控制组 supervisor 中。
+///
+/// explicit 为 false 时上级控制组即为沙盒自身所在的控制组：
+/// - 根控制组（包括容器中 cgroup namespace 的根）直接启用 controller
+/// - 已委派的控制组只修改其自身，其上级（如 system.slice）由 systemd 管理，不会被修改
+/// - 未委派时，只有不受 systemd 管理、以 root 运行且层级可写的主机会像指定 --cgroup-parent 一样逐级启用，否则报错
+fn prepare_v2_parent(root: &Path, parent: &Path, explicit: bool, controllers: &[&str]) -> Result<()> {
+    if !explicit && parent != root && !delegated(parent) {
+        if !writable_hierarchy(parent) {
+            return Err(Error::CGroupError(format!("{:?} is not delegated, {}", parent, DELEGATE_HINT)));
+        }
+        debug!("{:?} is not delegated, enabling controllers from the root", parent);
+    } else if !explicit {
+        return enable_controllers(root, parent, controllers);
+    }
+    let mut path = root.to_path_buf();
+    enable_controllers(root, &path, controllers)?;
+    for component in parent.strip_prefix(root).unwrap().components() {
+        path.push(component);
+        if !path.exists() {
+            try_io!(fs::create_dir(&path));
+        }
+        enable_controllers(root, &path, controllers)?;
+    }
+    Ok(())
+}
+
+/// 未委派的控制组能否由沙盒自行管理：不受 systemd 管理（同 sd_booted），以 root 运行且控制组可写
+fn writable_hierarchy(path: &Path) -> bool {
+    if Path::new("/run/systemd/system").exists() || unsafe { libc::geteuid() } != 0 {
+        return false;
+    }
+    let control = match std::ffi::CString::new(path.join("cgroup.subtree_control").as_os_str().as_bytes()) {
+        Ok(control) => control,
+        Err(_) => return false,
+    };
+    unsafe { libc::access(control.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// 在 cgroup.subtree_control 中启用 controller
+///
+/// 控制组中有进程时无法启用，此时若沙盒自身位于其中，则先移动到 supervisor 中再重试，
+/// 真正的根控制组不受此限制，沙盒不会被移动
+fn enable_controllers(root: &Path, path: &Path, controllers: &[&str]) -> Result<()> {
+    let available = try_io!(read_to_string(path.join("cgroup.controllers")));
+    let enabled = try_io!(read_to_string(path.join("cgroup.subtree_control")));
+    let enable: Vec<String> = controllers
+        .iter()
+        .filter(|c| available.split_whitespace().any(|a| a == **c))
+        .filter(|c| !enabled.split_whitespace().any(|e| e == **c))
+        .map(|c| format!("+{}", c))
+        .collect();
+    if enable.is_empty() {
+        return Ok(());
+    }
+    let mut result = fs::write(path.join("cgroup.subtree_control"), enable.join(" "));
+    let own = self_cgroup(None).map(|c| join_root(root, &c));
+    if result.is_err() && own.as_deref() == Some(path) {
+        let supervisor = path.join(SUPERVISOR_CGROUP);
+        if !supervisor.exists() {
+            try_io!(fs::create_dir(&supervisor));
+        }
+        try_io!(fs::write(supervisor.join("cgroup.procs"), "0"));
+        debug!("moved supervisor into {:?}", supervisor);
+        result = fs::write(path.join("cgroup.subtree_control"), enable.join(" "));
+    }
+    if let Err(e) = result {
+        return Err(Error::CGroupError(format!(
+            "cannot enable {} in {:?}: {}, the cgroup must not contain any process, {}",
+            enable.join(" "),
+            path,
+            e,
+            DELEGATE_HINT
+        )));
+    }
+    Ok(())
+}
+
+impl CGroupV2 {
+    /// kill 控制组中的所有进程，等待控制组为空后删除目录
+    fn teardown(&mut self) -> Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        destroy_cgroup(&std::mem::take(&mut self.path))
     }
 }
 
 impl Drop for CGroupV2 {
     fn drop(&mut self) {
-        // 将当前控制组里所有进程移动到全局 root 节点
-        let pids = read_to_string(self.path.join("cgroup.procs")).unwrap();
-        fs::write("/sys/fs/cgroup/cgroup.procs", pids).unwrap();
-        remove_dir(&self.path).unwrap();
+        if let Err(e) = self.teardown() {
+            error!("failed to destroy cgroup: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    fn name(pid: u32, start_time: u64) -> String {
+        format!("{}{}-{}-{}-abc123", CGROUP_PREFIX, pid_namespace().unwrap(), pid, start_time)
+    }
+
+    #[test]
+    fn parse_proc_stat() {
+        let stat = "1234 (a) (b c) S 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 98765 123456 100\n";
+        assert_eq!(parse_stat(stat), Some(('S', 98765)));
+        assert_eq!(parse_stat("1234 (sleep) Z 1 1234 1234"), None);
+        assert_eq!(parse_stat("1234 sleep S"), None);
+        assert!(process_stat("self").is_some());
+    }
+
+    #[test]
+    fn orphan_names() {
+        let (_, start_time) = process_stat("self").unwrap();
+        assert!(!is_orphan("user.slice"));
+        assert!(!is_orphan(&format!("{}abc", CGROUP_PREFIX)));
+        // 缺少启动时间的名称无法判断
+        assert!(!is_orphan(&format!("{}{}-1-abc123", CGROUP_PREFIX, pid_namespace().unwrap())));
+        assert!(!is_orphan(&format!("{}1-1-1-abc123", CGROUP_PREFIX)));
+        assert!(!is_orphan(&name(std::process::id(), start_time)));
+    }
+
+    #[test]
+    fn blkio_service_bytes() {
+        let content = "8:0 Read 4096
+8:0 Write 512
+8:0 Sync 4608
+8:0 Async 0
+8:0 Discard 0
+8:0 Total 4608
+8:16 Read 100
+8:16 Write 0
+8:16 Total 100
+Total 4708
+";
+        assert_eq!(blkio_bytes(content), (4196, 512));
+        // 没有任何 I/O 时只有 Total 一行
+        assert_eq!(blkio_bytes("Total 0\n"), (0, 0));
+        assert_eq!(blkio_bytes(""), (0, 0));
+    }
+
+    #[test]
+    fn io_stat() {
+        let content = "8:0 rbytes=4096 wbytes=1024 rios=1 wios=2 dbytes=0 dios=0
+8:16 rbytes=100 wbytes=0 rios=1 wios=0 dbytes=0 dios=0
+253:0 wbytes=7
+";
+        assert_eq!(io_stat_bytes(content), (4196, 1031));
+        assert_eq!(io_stat_bytes(""), (0, 0));
+        assert_eq!(io_stat_bytes("8:0 rios=1 wios=1\n"), (0, 0));
+    }
+
+    #[test]
+    fn keys() {
+        let content = "populated 1\nfrozen 0\noom 2\noom_kill 3\n";
+        assert_eq!(parse_key(content, "oom"), Some(2));
+        assert_eq!(parse_key(content, "oom_kill"), Some(3));
+        assert_eq!(parse_key(content, "max"), None);
+        assert_eq!(parse_key("max invalid\n", "max"), None);
+        assert_eq!(read_key(Path::new("/nonexistent/pids.events"), "max"), None);
+        assert_eq!(read_event(Path::new("/nonexistent/pids.events"), "max"), 0);
+    }
+
+    #[test]
+    fn orphan_creator() {
+        let mut child = Command::new("sleep").arg("10").stdin(Stdio::null()).spawn().unwrap();
+        let (_, start_time) = process_stat(&child.id().to_string()).unwrap();
+        // 与当前进程的程序名不同的存活进程不是遗弃的控制组
+        assert!(!is_orphan(&name(child.id(), start_time)));
+        // pid 被复用时启动时间不同
+        assert!(is_orphan(&name(child.id(), start_time + 1)));
+
+        child.kill().unwrap();
+        // 尚未被回收的进程
+        while process_stat(&child.id().to_string()).unwrap().0 != 'Z' {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(is_orphan(&name(child.id(), start_time)));
+        child.wait().unwrap();
+        assert!(is_orphan(&name(child.id(), start_time)));
     }
 }
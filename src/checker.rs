@@ -0,0 +1,518 @@
+use std::fmt;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::mount::Bind;
+use crate::sandbox::Sandbox;
+use crate::status::{RunnerStatus, Verdict};
+
+/// 程序输出与标准答案的比较方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareMode {
+    /// 逐字节比较
+    Exact,
+    /// 以空白字符分隔后逐个比较，忽略所有空白的差异
+    Tokens,
+    /// 逐行比较，忽略行末空白与文件末尾的空行
+    Lines,
+    /// 同 Tokens，但两者都是数字时允许存在误差，满足绝对误差或相对误差之一即可
+    Float { absolute: f64, relative: f64 },
+}
+
+impl FromStr for CompareMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(CompareMode::Exact),
+            "token" => Ok(CompareMode::Tokens),
+            "line" => Ok(CompareMode::Lines),
+            "float" => Ok(CompareMode::Float {
+                absolute: DEFAULT_EPSILON,
+                relative: DEFAULT_EPSILON,
+            }),
+            _ => Err(format!("unknown compare mode `{}`, expected exact, token, line or float", s)),
+        }
+    }
+}
+
+/// float 模式默认允许的绝对误差与相对误差
+pub const DEFAULT_EPSILON: f64 = 1e-6;
+
+/// 输出中的位置，行与列均从 1 开始，列以字节计
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    /// 距离输出开头的字节数
+    pub offset: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// 比较的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    /// Accepted、WrongAnswer、PresentationError，特殊评测程序还可能给出 PartiallyCorrect 或 SystemError
+    pub verdict: Verdict,
+    pub message: String,
+    /// 特殊评测程序给出的部分分，仅在 PartiallyCorrect 时存在
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// 输出中第一处与答案不同的位置，答案正确时为 None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+}
+
+impl CheckResult {
+    fn accepted() -> Self {
+        CheckResult {
+            verdict: Verdict::Accepted,
+            message: String::from("ok"),
+            score: None,
+            position: None,
+        }
+    }
+
+    fn wrong(output: &[u8], offset: usize, message: String) -> Self {
+        CheckResult {
+            verdict: Verdict::WrongAnswer,
+            message,
+            score: None,
+            position: Some(position(output, offset)),
+        }
+    }
+}
+
+/// 比较程序输出与标准答案
+///
+/// exact 与 line 模式下内容不同但忽略空白后相同时判定为 PresentationError
+pub fn check(output: &[u8], answer: &[u8], mode: CompareMode) -> CheckResult {
+    let result = match mode {
+        CompareMode::Exact => check_exact(output, answer),
+        CompareMode::Tokens => check_tokens(output, answer, None),
+        CompareMode::Lines => check_lines(output, answer),
+        CompareMode::Float { absolute, relative } => check_tokens(output, answer, Some((absolute, relative))),
+    };
+    if result.verdict == Verdict::WrongAnswer
+        && matches!(mode, CompareMode::Exact | CompareMode::Lines)
+        && check_tokens(output, answer, None).verdict == Verdict::Accepted
+    {
+        return CheckResult {
+            verdict: Verdict::PresentationError,
+            ..result
+        };
+    }
+    result
+}
+
+fn check_exact(output: &[u8], answer: &[u8]) -> CheckResult {
+    match output.iter().zip(answer).position(|(a, b)| a != b) {
+        Some(offset) => CheckResult::wrong(
+            output,
+            offset,
+            format!("expected byte {:?}, found {:?}", answer[offset] as char, output[offset] as char),
+        ),
+        None if output.len() > answer.len() => {
+            CheckResult::wrong(output, answer.len(), String::from("output is longer than the answer"))
+        }
+        None if output.len() < answer.len() => {
+            CheckResult::wrong(output, output.len(), String::from("output is shorter than the answer"))
+        }
+        None => CheckResult::accepted(),
+    }
+}
+
+fn check_lines(output: &[u8], answer: &[u8]) -> CheckResult {
+    let output_lines = trimmed_lines(output);
+    let answer_lines = trimmed_lines(answer);
+    for (i, answer_line) in answer_lines.iter().enumerate() {
+        let (offset, line) = match output_lines.get(i) {
+            Some(line) => *line,
+            None => {
+                return CheckResult::wrong(
+                    output,
+                    output.len(),
+                    format!("output has {} lines, expected {}", output_lines.len(), answer_lines.len()),
+                )
+            }
+        };
+        let line_answer = answer_line.1;
+        if line != line_answer {
+            let column = line.iter().zip(line_answer).take_while(|(a, b)| a == b).count();
+            return CheckResult::wrong(
+                output,
+                offset + column,
+                format!(
+                    "line {} differs, expected {:?}, found {:?}",
+                    i + 1,
+                    String::from_utf8_lossy(line_answer),
+                    String::from_utf8_lossy(line)
+                ),
+            );
+        }
+    }
+    if output_lines.len() > answer_lines.len() {
+        let (offset, _) = output_lines[answer_lines.len()];
+        return CheckResult::wrong(
+            output,
+            offset,
+            format!("output has {} lines, expected {}", output_lines.len(), answer_lines.len()),
+        );
+    }
+    CheckResult::accepted()
+}
+
+/// 按行切分并去除行末空白，末尾的空行会被忽略，返回每行的起始偏移与内容
+fn trimmed_lines(data: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    for line in data.split(|c| *c == b'\n') {
+        let end = line.iter().rposition(|c| !c.is_ascii_whitespace()).map_or(0, |i| i + 1);
+        lines.push((offset, &line[..end]));
+        offset += line.len() + 1;
+    }
+    while matches!(lines.last(), Some((_, line)) if line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+fn check_tokens(output: &[u8], answer: &[u8], epsilon: Option<(f64, f64)>) -> CheckResult {
+    let output_tokens = tokens(output);
+    let answer_tokens = tokens(answer);
+    for (i, (_, expected)) in answer_tokens.iter().enumerate() {
+        let found = match output_tokens.get(i) {
+            Some((_, found)) => *found,
+            None => {
+                return CheckResult::wrong(
+                    output,
+                    output.len(),
+                    format!("output has {} tokens, expected {}", output_tokens.len(), answer_tokens.len()),
+                )
+            }
+        };
+        let equal = match epsilon {
+            Some((absolute, relative)) => float_equal(found, expected, absolute, relative),
+            None => found == *expected,
+        };
+        if !equal {
+            return CheckResult::wrong(
+                output,
+                output_tokens[i].0,
+                format!(
+                    "token {} differs, expected {:?}, found {:?}",
+                    i + 1,
+                    String::from_utf8_lossy(expected),
+                    String::from_utf8_lossy(found)
+                ),
+            );
+        }
+    }
+    if output_tokens.len() > answer_tokens.len() {
+        return CheckResult::wrong(
+            output,
+            output_tokens[answer_tokens.len()].0,
+            format!("output has {} tokens, expected {}", output_tokens.len(), answer_tokens.len()),
+        );
+    }
+    CheckResult::accepted()
+}
+
+/// 以空白字符切分，返回每个 token 的起始偏移与内容
+fn tokens(data: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in data.iter().enumerate() {
+        match (c.is_ascii_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((s, &data[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s, &data[s..]));
+    }
+    tokens
+}
+
+/// 两者都能解析为有限的数字时按误差比较，否则按内容比较
+fn float_equal(found: &[u8], expected: &[u8], absolute: f64, relative: f64) -> bool {
+    let parse = |token: &[u8]| {
+        std::str::from_utf8(token)
+            .ok()
+            .and_then(|token| token.parse::<f64>().ok())
+            .filter(|value| value.is_finite())
+    };
+    match (parse(found), parse(expected)) {
+        (Some(found), Some(expected)) => {
+            let diff = (found - expected).abs();
+            diff <= absolute || diff <= relative * expected.abs()
+        }
+        _ => found == expected,
+    }
+}
+
+/// 将字节偏移换算为行与列
+fn position(data: &[u8], offset: usize) -> Position {
+    let before = &data[..offset.min(data.len())];
+    let line = before.iter().filter(|c| **c == b'\n').count() + 1;
+    let column = match before.iter().rposition(|c| *c == b'\n') {
+        Some(i) => offset - i,
+        None => offset + 1,
+    };
+    Position { line, column, offset }
+}
+
+/// testlib 约定的评测程序退出码
+const TESTLIB_OK: i32 = 0;
+const TESTLIB_WRONG_ANSWER: i32 = 1;
+const TESTLIB_PRESENTATION_ERROR: i32 = 2;
+const TESTLIB_FAIL: i32 = 3;
+const TESTLIB_POINTS: i32 = 7;
+
+/// 评测程序输出的信息最多保留的长度，单位 byte
+const MESSAGE_LIMIT: u64 = 64 * 1024;
+
+/// 在沙盒中运行 testlib 风格的特殊评测程序
+///
+/// 输入、程序输出与答案会被复制到评测程序的工作目录中，并以只读方式绑定挂载交给评测程序，
+/// 调用方式为 `checker /tmp/input.txt /tmp/output.txt /tmp/answer.txt`，运行结束后目录会被删除。
+/// 评测程序的标准错误作为结果的信息，退出码按 testlib 的约定对应到结果：
+///
+/// - 0 为 Accepted，1 为 WrongAnswer，2 为 PresentationError
+/// - 3 表示评测程序自身出错，判定为 SystemError
+/// - 7 为 PartiallyCorrect，分数位于信息的开头，如 `points 0.5 ...`
+///
+/// 评测程序超时或以其他方式结束时同样判定为 SystemError
+pub fn special_judge(checker: &mut Sandbox, input: &[u8], output: &[u8], answer: &[u8]) -> Result<CheckResult> {
+    let dir = try_io!(tempfile::Builder::new().prefix("newbie-checker-").tempdir());
+    let mut args = vec![];
+    let mut binds = vec![];
+    for (name, data) in &[("input.txt", input), ("output.txt", output), ("answer.txt", answer)] {
+        let path = dir.path().join(name);
+        try_io!(fs::write(&path, data));
+        // 工作目录对沙盒内的程序可写，文件本身为只读挂载点，无法被修改、删除或替换
+        let target = format!("/tmp/{}", name);
+        binds.push(Bind::read_only(path.to_string_lossy().to_string(), target.clone()));
+        args.push(target);
+    }
+    let null = try_io!(fs::OpenOptions::new().write(true).open("/dev/null"));
+
+    let saved = Environment::replace(checker, dir.path().to_string_lossy().to_string(), args, binds, null.as_raw_fd());
+    let status = checker.execute();
+    saved.restore(checker);
+    Ok(testlib_result(&status?))
+}
+
+/// 根据评测程序的退出码与标准错误得出结果
+fn testlib_result(status: &RunnerStatus) -> CheckResult {
+    let message = String::from_utf8_lossy(status.stderr.as_deref().unwrap_or_default()).trim().to_string();
+    let exit_code = match status.verdict {
+        Verdict::Accepted | Verdict::RuntimeError if status.signal == 0 => status.exit_code,
+        verdict if message.is_empty() => return checker_failed(format!("checker finished with {}", verdict)),
+        verdict => return checker_failed(format!("checker finished with {}: {}", verdict, message)),
+    };
+    let verdict = match exit_code {
+        TESTLIB_OK => Verdict::Accepted,
+        TESTLIB_WRONG_ANSWER => Verdict::WrongAnswer,
+        TESTLIB_PRESENTATION_ERROR => Verdict::PresentationError,
+        TESTLIB_POINTS => {
+            return match points(&message) {
+                Some(score) => CheckResult {
+                    verdict: Verdict::PartiallyCorrect,
+                    message,
+                    score: Some(score),
+                    position: None,
+                },
+                None => checker_failed(format!("checker reported points without a score: {}", message)),
+            }
+        }
+        TESTLIB_FAIL => return checker_failed(message),
+        code => return checker_failed(format!("checker exited with unknown code {}: {}", code, message)),
+    };
+    CheckResult {
+        verdict,
+        message,
+        score: None,
+        position: None,
+    }
+}
+
+fn checker_failed(message: String) -> CheckResult {
+    CheckResult {
+        verdict: Verdict::SystemError,
+        message,
+        score: None,
+        position: None,
+    }
+}
+
+/// 解析 testlib 的 quitp 输出的分数，如 `points 0.5 partially correct`
+fn points(message: &str) -> Option<f64> {
+    let message = message.strip_prefix("points").unwrap_or(message);
+    message.split_whitespace().next()?.parse::<f64>().ok().filter(|score| score.is_finite())
+}
+
+/// 运行评测程序前沙盒原有的设置
+struct Environment {
+    workdir: String,
+    args: usize,
+    binds: usize,
+    stdin_data: Option<Vec<u8>>,
+    stdout_fd: i32,
+    stdout_limit: Option<u64>,
+    capture_stdout: bool,
+    capture_stderr: bool,
+    stderr_limit: Option<u64>,
+}
+
+impl Environment {
+    /// 将工作目录替换为存放文件的临时目录，挂载文件并在命令行末尾追加文件路径
+    ///
+    /// 评测程序的标准输入为空，标准输出被重定向到 null，标准错误被捕获作为结果的信息
+    fn replace(checker: &mut Sandbox, workdir: String, args: Vec<String>, binds: Vec<Bind>, null: i32) -> Self {
+        let saved = Environment {
+            workdir: std::mem::replace(&mut checker.workdir, workdir),
+            args: checker.inner_args.len(),
+            binds: checker.binds.len(),
+            stdin_data: checker.stdin_data.replace(vec![]),
+            stdout_fd: checker.stdout_fd,
+            stdout_limit: checker.stdout_limit,
+            capture_stdout: checker.capture_stdout,
+            capture_stderr: checker.capture_stderr,
+            stderr_limit: checker.stderr_limit,
+        };
+        checker.inner_args.extend(args);
+        checker.binds.extend(binds);
+        checker.stdout_fd = null;
+        checker.stdout_limit = None;
+        checker.capture_stdout = false;
+        checker.capture_stderr = true;
+        checker.stderr_limit = Some(MESSAGE_LIMIT);
+        saved
+    }
+
+    fn restore(self, checker: &mut Sandbox) {
+        checker.workdir = self.workdir;
+        checker.inner_args.truncate(self.args);
+        checker.binds.truncate(self.binds);
+        checker.stdin_data = self.stdin_data;
+        checker.stdout_fd = self.stdout_fd;
+        checker.stdout_limit = self.stdout_limit;
+        checker.capture_stdout = self.capture_stdout;
+        checker.capture_stderr = self.capture_stderr;
+        checker.stderr_limit = self.stderr_limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLOAT: CompareMode = CompareMode::Float {
+        absolute: DEFAULT_EPSILON,
+        relative: DEFAULT_EPSILON,
+    };
+
+    fn verdict(output: &str, answer: &str, mode: CompareMode) -> Verdict {
+        check(output.as_bytes(), answer.as_bytes(), mode).verdict
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(verdict("1 2\n", "1 2\n", CompareMode::Exact), Verdict::Accepted);
+        assert_eq!(verdict("1 3\n", "1 2\n", CompareMode::Exact), Verdict::WrongAnswer);
+        assert_eq!(verdict("1 2", "1 2\n", CompareMode::Exact), Verdict::PresentationError);
+        assert_eq!(verdict("1 2 \n", "1 2\n", CompareMode::Exact), Verdict::PresentationError);
+        assert_eq!(verdict("1  2\n", "1 2\n", CompareMode::Exact), Verdict::PresentationError);
+        assert_eq!(verdict("1 2\n3\n", "1 2\n", CompareMode::Exact), Verdict::WrongAnswer);
+        assert_eq!(verdict("", "", CompareMode::Exact), Verdict::Accepted);
+    }
+
+    #[test]
+    fn lines_ignore_trailing_whitespace() {
+        assert_eq!(verdict("1 2 \t\n3\r\n", "1 2\n3\n", CompareMode::Lines), Verdict::Accepted);
+        assert_eq!(verdict("1 2", "1 2\n", CompareMode::Lines), Verdict::Accepted);
+        assert_eq!(verdict("1 2\n\n\n", "1 2\n", CompareMode::Lines), Verdict::Accepted);
+        assert_eq!(verdict("1 2\n", "1 2\n\n \n", CompareMode::Lines), Verdict::Accepted);
+        assert_eq!(verdict("\n", "", CompareMode::Lines), Verdict::Accepted);
+    }
+
+    #[test]
+    fn lines_presentation_error() {
+        // 行首空白、行中空白与中间的空行不会被忽略
+        assert_eq!(verdict(" 1 2\n", "1 2\n", CompareMode::Lines), Verdict::PresentationError);
+        assert_eq!(verdict("1  2\n", "1 2\n", CompareMode::Lines), Verdict::PresentationError);
+        assert_eq!(verdict("1\n\n2\n", "1\n2\n", CompareMode::Lines), Verdict::PresentationError);
+        assert_eq!(verdict("1 2\n", "1\n2\n", CompareMode::Lines), Verdict::PresentationError);
+        assert_eq!(verdict("1 3\n", "1 2\n", CompareMode::Lines), Verdict::WrongAnswer);
+        assert_eq!(verdict("1\n", "1\n2\n", CompareMode::Lines), Verdict::WrongAnswer);
+        assert_eq!(verdict("1\n2\n", "1\n", CompareMode::Lines), Verdict::WrongAnswer);
+    }
+
+    #[test]
+    fn tokens_ignore_whitespace() {
+        assert_eq!(verdict(" 1\n\n2\t3 ", "1 2 3\n", CompareMode::Tokens), Verdict::Accepted);
+        assert_eq!(verdict("", "\n \n", CompareMode::Tokens), Verdict::Accepted);
+        assert_eq!(verdict("1 2", "1 2 3", CompareMode::Tokens), Verdict::WrongAnswer);
+        assert_eq!(verdict("1 2 3", "1 2", CompareMode::Tokens), Verdict::WrongAnswer);
+        // token 模式没有格式错误
+        assert_eq!(verdict("12", "1 2", CompareMode::Tokens), Verdict::WrongAnswer);
+    }
+
+    #[test]
+    fn float_epsilon() {
+        assert_eq!(verdict("1.0000001", "1", FLOAT), Verdict::Accepted);
+        assert_eq!(verdict("1.000001", "1", FLOAT), Verdict::Accepted);
+        assert_eq!(verdict("1.0000011", "1", FLOAT), Verdict::WrongAnswer);
+        assert_eq!(verdict("-0.0000005", "0", FLOAT), Verdict::Accepted);
+        // 绝对误差不满足时，按相对误差比较
+        assert_eq!(verdict("1000000.5", "1000000", FLOAT), Verdict::Accepted);
+        assert_eq!(verdict("1000002", "1000000", FLOAT), Verdict::WrongAnswer);
+        assert_eq!(verdict("1e3", "1000", FLOAT), Verdict::Accepted);
+    }
+
+    #[test]
+    fn float_non_numbers() {
+        assert_eq!(verdict("yes 1.0", "yes 1", FLOAT), Verdict::Accepted);
+        assert_eq!(verdict("no 1", "yes 1", FLOAT), Verdict::WrongAnswer);
+        // 非有限的数字按内容比较
+        assert_eq!(verdict("nan", "nan", FLOAT), Verdict::Accepted);
+        assert_eq!(verdict("inf", "1e308", FLOAT), Verdict::WrongAnswer);
+    }
+
+    #[test]
+    fn positions() {
+        let result = check(b"ab\ncd\n", b"ab\nce\n", CompareMode::Exact);
+        assert_eq!(result.position, Some(Position { line: 2, column: 2, offset: 4 }));
+        let result = check(b"1 2\n3 5\n", b"1 2\n3 4\n", CompareMode::Tokens);
+        assert_eq!(result.position, Some(Position { line: 2, column: 3, offset: 6 }));
+        let result = check(b"1\n2 \n3\n", b"1\n2\n4\n", CompareMode::Lines);
+        assert_eq!(result.position, Some(Position { line: 3, column: 1, offset: 5 }));
+        // 输出过短时位于输出末尾
+        let result = check(b"1\n", b"1\n2\n", CompareMode::Lines);
+        assert_eq!(result.position, Some(Position { line: 2, column: 1, offset: 2 }));
+        assert_eq!(check(b"1\n", b"1\n", CompareMode::Exact).position, None);
+    }
+
+    #[test]
+    fn testlib_points() {
+        assert_eq!(points("points 0.5 partially correct"), Some(0.5));
+        assert_eq!(points("0.25"), Some(0.25));
+        assert_eq!(points("points"), None);
+        assert_eq!(points("points nan"), None);
+        assert_eq!(points("partially correct"), None);
+    }
+}
@@ -7,10 +7,21 @@ use std::result;
 use libc::strerror;
 
 #[allow(dead_code)]
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     IOError(io::Error),
     StringToCStringError(NulError),
+    /// 运行结果序列化为 json 或 toml 失败
+    SerializeError(String),
+    /// 控制组不可用，如版本不匹配或缺少所需的 controller
+    CGroupError(String),
+    /// 测试点的输入或答案文件不存在
+    TestCaseError(String),
+    /// 语言预设无法解析，或指定的语言不存在
+    PresetError(String),
+    /// 挂载的配置无效，如外部路径不存在
+    MountError(String),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -19,6 +30,11 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::IOError(ref e) => write!(f, "IOError: `{}`", errno_str(e.raw_os_error())),
+            Error::SerializeError(ref e) => write!(f, "SerializeError: `{}`", e),
+            Error::CGroupError(ref e) => write!(f, "CGroupError: `{}`", e),
+            Error::TestCaseError(ref e) => write!(f, "TestCaseError: `{}`", e),
+            Error::PresetError(ref e) => write!(f, "PresetError: `{}`", e),
+            Error::MountError(ref e) => write!(f, "MountError: `{}`", e),
             _ => write!(f, "{:?}", self),
         }
     }
@@ -16,7 +16,8 @@ pub struct ExecArgs {
 }
 
 impl ExecArgs {
-    pub fn build(args: &Vec<String>) -> Result<ExecArgs> {
+    /// envs 为额外的环境变量，与默认环境变量同名时覆盖默认值
+    pub fn build(args: &[String], extra_envs: &[(String, String)]) -> Result<ExecArgs> {
         let pathname = args[0].clone();
         let pathname_str = try_cstr!(pathname);
         let pathname = pathname_str.as_ptr();
@@ -44,6 +45,9 @@ impl ExecArgs {
         envs.insert("RUSTUP_HOME", "/root/.rustup");
         envs.insert("CARGO_HOME", "/root/.cargo");
         envs.insert("TERM", "xterm");
+        for (key, value) in extra_envs {
+            envs.insert(key, value);
+        }
         let mut envp_vec: Vec<*const libc::c_char> = vec![];
         for (key, value) in envs {
             let mut key = String::from(key);
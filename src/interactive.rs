@@ -0,0 +1,154 @@
+use std::thread;
+use std::time::Instant;
+
+use crate::error::Result;
+use crate::output;
+use crate::sandbox::Sandbox;
+use crate::status::{RunnerStatus, Verdict};
+
+/// 交互模式：同时运行选手程序与交互程序，并将两者的标准输入输出交叉连接
+///
+/// 两个沙盒各自使用自己的资源限制，原有的标准输入与标准输出设置会在运行期间被管道替代。
+/// 返回选手程序的运行结果，其 verdict 为综合两者得出的最终结果，交互程序的运行结果位于 interactor 中，
+/// 完整的结果会输出到选手沙盒的 result_fd
+pub fn run(contestant: &mut Sandbox, interactor: &mut Sandbox) -> Result<RunnerStatus> {
+    let start = Instant::now();
+    // 选手程序的输出 -> 交互程序的输入
+    let (interactor_stdin, contestant_stdout) = output::pipe()?;
+    // 交互程序的输出 -> 选手程序的输入
+    let (contestant_stdin, interactor_stdout) = output::pipe()?;
+
+    let contestant_saved = Connection::replace(contestant, contestant_stdin, contestant_stdout);
+    let interactor_saved = Connection::replace(interactor, interactor_stdin, interactor_stdout);
+    // 两个沙盒必须在同一线程中依次创建，此时不能有其他线程在运行，创建完成后再启动转发线程
+    let contestant_running = contestant.spawn();
+    contestant.close_handover_fds();
+    let interactor_running = interactor.spawn();
+    interactor.close_handover_fds();
+    let (contestant_status, interactor_status) = match (contestant_running, interactor_running) {
+        (Ok(contestant_running), Ok(interactor_running)) => thread::scope(|scope| {
+            let interactor = scope.spawn(|| interactor.wait(interactor_running));
+            (contestant.wait(contestant_running), join(interactor))
+        }),
+        // 其中一个创建失败时，另一个的管道对端已经关闭，会自行结束
+        (contestant_running, interactor_running) => (
+            contestant_running.and_then(|running| contestant.wait(running)),
+            interactor_running.and_then(|running| interactor.wait(running)),
+        ),
+    };
+    contestant_saved.restore(contestant);
+    interactor_saved.restore(interactor);
+
+    let mut status = contestant_status?;
+    let interactor_status = interactor_status?;
+    status.verdict = verdict(&status, &interactor_status);
+    status.interactor = Some(Box::new(interactor_status));
+    contestant.write_result(&status, start)?;
+    Ok(status)
+}
+
+/// 沙盒内部的 panic 原样传递给调用方
+fn join(handle: thread::ScopedJoinHandle<Result<RunnerStatus>>) -> Result<RunnerStatus> {
+    handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+}
+
+/// 根据两个程序各自的结果得出最终结果
+///
+/// - 选手程序超出资源限制或违反安全规则时，交互程序的结果通常只是由此导致的，以选手程序为准
+/// - 交互程序以非 0 退出码正常结束时判定为 WrongAnswer，优先于选手程序因管道被关闭（SIGPIPE）导致的 RuntimeError
+/// - 交互程序自身异常结束（超时、被信号结束等）时无法得出结论，判定为 SystemError
+/// - 交互程序接受时以选手程序的结果为准
+pub fn verdict(contestant: &RunnerStatus, interactor: &RunnerStatus) -> Verdict {
+    match contestant.verdict {
+        Verdict::TimeLimitExceeded
+        | Verdict::MemoryLimitExceeded
+        | Verdict::OutputLimitExceeded
+        | Verdict::ProcessLimitExceeded
+        | Verdict::SecurityViolation
+        | Verdict::SystemError => return contestant.verdict,
+        _ => {}
+    }
+    match interactor.verdict {
+        Verdict::Accepted => contestant.verdict,
+        Verdict::RuntimeError if interactor.signal == 0 => Verdict::WrongAnswer,
+        _ => Verdict::SystemError,
+    }
+}
+
+/// 运行前沙盒原有的标准输入输出设置
+struct Connection {
+    stdin_fd: i32,
+    stdin_data: Option<Vec<u8>>,
+    stdout_fd: i32,
+    capture_stdout: bool,
+}
+
+impl Connection {
+    /// 将沙盒的标准输入输出替换为管道，管道在沙盒创建后由其自行关闭
+    fn replace(sandbox: &mut Sandbox, stdin: i32, stdout: i32) -> Self {
+        let saved = Connection {
+            stdin_fd: sandbox.stdin_fd,
+            stdin_data: sandbox.stdin_data.take(),
+            stdout_fd: sandbox.stdout_fd,
+            capture_stdout: sandbox.capture_stdout,
+        };
+        sandbox.stdin_fd = stdin;
+        sandbox.stdout_fd = stdout;
+        sandbox.capture_stdout = false;
+        sandbox.handover_fds = vec![stdin, stdout];
+        saved
+    }
+
+    fn restore(self, sandbox: &mut Sandbox) {
+        sandbox.stdin_fd = self.stdin_fd;
+        sandbox.stdin_data = self.stdin_data;
+        sandbox.stdout_fd = self.stdout_fd;
+        sandbox.capture_stdout = self.capture_stdout;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    fn status(verdict: Verdict, signal: i32) -> RunnerStatus {
+        let mut status = RunnerStatus::new(0, &utils::new_rusage());
+        status.verdict = verdict;
+        status.signal = signal;
+        status
+    }
+
+    #[test]
+    fn verdicts() {
+        use Verdict::*;
+        let cases = [
+            // (选手程序, 信号), (交互程序, 信号), 最终结果
+            ((Accepted, 0), (Accepted, 0), Accepted),
+            ((RuntimeError, 0), (Accepted, 0), RuntimeError),
+            // 交互程序判定错误后关闭管道，选手程序因 SIGPIPE 结束
+            ((RuntimeError, libc::SIGPIPE), (RuntimeError, 0), WrongAnswer),
+            ((Accepted, 0), (RuntimeError, 0), WrongAnswer),
+            ((TimeLimitExceeded, libc::SIGKILL), (RuntimeError, 0), TimeLimitExceeded),
+            ((MemoryLimitExceeded, libc::SIGKILL), (Accepted, 0), MemoryLimitExceeded),
+            ((OutputLimitExceeded, 0), (RuntimeError, 0), OutputLimitExceeded),
+            ((ProcessLimitExceeded, 0), (RuntimeError, 0), ProcessLimitExceeded),
+            ((SecurityViolation, libc::SIGSYS), (RuntimeError, 0), SecurityViolation),
+            ((SystemError, 0), (Accepted, 0), SystemError),
+            // 交互程序自身异常结束
+            ((Accepted, 0), (RuntimeError, libc::SIGSEGV), SystemError),
+            ((RuntimeError, libc::SIGPIPE), (TimeLimitExceeded, libc::SIGKILL), SystemError),
+            ((Accepted, 0), (MemoryLimitExceeded, libc::SIGKILL), SystemError),
+            ((WrongAnswer, 0), (SecurityViolation, libc::SIGSYS), SystemError),
+        ];
+        for ((contestant, contestant_signal), (interactor, interactor_signal), expected) in cases.iter() {
+            assert_eq!(
+                verdict(&status(*contestant, *contestant_signal), &status(*interactor, *interactor_signal)),
+                *expected,
+                "{} {}",
+                contestant,
+                interactor
+            );
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use tempfile::TempDir;
+
+use crate::error::{Error, Result};
+use crate::runit::DENIED_SYSCALLS;
+use crate::sandbox::Sandbox;
+use crate::status::{CompileStatus, Limits, RunnerStatus, Verdict};
+use crate::utils;
+
+/// 编译器输出最多保留的长度，单位 byte
+const MESSAGE_LIMIT: u64 = 64 * 1024;
+
+/// 一种语言的编译与运行方式
+///
+/// 命令在沙盒内部的工作目录（/tmp）中执行，源代码会以 source 指定的文件名放在该目录下
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    /// 源代码的文件名，如 `main.cpp`、`Main.java`
+    pub source: String,
+    /// 编译命令，解释型语言可以省略
+    #[serde(default)]
+    pub compile: Option<Vec<String>>,
+    /// 运行命令
+    pub run: Vec<String>,
+    #[serde(default)]
+    pub compile_limits: PresetLimits,
+    /// 运行时的默认资源限制，命令行中指定的限制优先
+    #[serde(default)]
+    pub run_limits: PresetLimits,
+    /// 运行时间限制的倍数，如 Java 通常为 2
+    #[serde(default = "default_multiplier")]
+    pub time_multiplier: f64,
+    /// 编译与运行时额外的环境变量
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// 编译与运行时允许使用的、默认会被阻止的系统调用
+    #[serde(default)]
+    pub syscalls: Vec<String>,
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+/// 预设中的资源限制，单位同 Sandbox，未指定的项不限制
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PresetLimits {
+    pub time_limit: Option<i32>,
+    pub real_time_limit: Option<i32>,
+    pub memory_limit: Option<i32>,
+    pub file_size_limit: Option<i32>,
+    pub stdout_limit: Option<u64>,
+    pub pids: Option<i32>,
+}
+
+/// 从 toml 文件中读取的语言预设，以语言名称作为表名
+///
+/// ```toml
+/// [cpp]
+/// source = "main.cpp"
+/// compile = ["/usr/bin/g++", "-O2", "-o", "main", "main.cpp"]
+/// run = ["/tmp/main"]
+///
+/// [cpp.compile_limits]
+/// time_limit = 10000
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    presets: BTreeMap<String, Preset>,
+}
+
+impl Registry {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = try_io!(fs::read_to_string(path));
+        Self::parse(&content).map_err(|e| match e {
+            Error::PresetError(e) => Error::PresetError(format!("{}: {}", path.display(), e)),
+            e => e,
+        })
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let presets: BTreeMap<String, Preset> = match toml::from_str(content) {
+            Ok(presets) => presets,
+            Err(e) => return Err(Error::PresetError(e.to_string())),
+        };
+        for (name, preset) in &presets {
+            preset.validate().map_err(|e| Error::PresetError(format!("language `{}`: {}", name, e)))?;
+        }
+        Ok(Registry { presets })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Preset> {
+        self.presets.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+            Error::PresetError(format!("unknown language `{}`, available: {}", name, names.join(", ")))
+        })
+    }
+
+    /// 所有语言的名称，按字典序排列
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(String::as_str)
+    }
+}
+
+impl Preset {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.run.is_empty() || matches!(&self.compile, Some(compile) if compile.is_empty()) {
+            return Err(String::from("command must not be empty"));
+        }
+        // 源代码会被复制到工作目录中，不能借此写入其他位置
+        if self.source.is_empty() || self.source.contains('/') {
+            return Err(format!("invalid source file name `{}`", self.source));
+        }
+        if !self.time_multiplier.is_finite() || self.time_multiplier <= 0.0 {
+            return Err(format!("invalid time multiplier {}", self.time_multiplier));
+        }
+        for syscall in &self.syscalls {
+            if !DENIED_SYSCALLS.iter().any(|(name, _)| name == syscall) {
+                return Err(format!("syscall `{}` is not denied by default", syscall));
+            }
+        }
+        Ok(())
+    }
+
+    /// 创建存放源代码与编译产物的临时工作目录，目录在返回值 drop 时删除
+    pub fn workspace(&self, source: &Path) -> Result<TempDir> {
+        let dir = try_io!(tempfile::Builder::new().prefix("newbie-judge-").tempdir());
+        try_io!(fs::copy(source, dir.path().join(&self.source)));
+        Ok(dir)
+    }
+
+    /// 编译所用的沙盒，已应用编译限制、环境变量与系统调用，解释型语言返回 None
+    ///
+    /// 编译器的输出会被捕获，rootfs、工作目录与控制组等由调用方设置
+    pub fn compiler(&self) -> Result<Option<Sandbox>> {
+        let command = match self.compile.clone() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let sandbox = self
+            .apply(Sandbox::new(command)?, &self.compile_limits)
+            .stdin_bytes(vec![])
+            .capture_stdout(MESSAGE_LIMIT)
+            .capture_stderr(MESSAGE_LIMIT);
+        Ok(Some(sandbox))
+    }
+
+    /// 运行所用的沙盒，已应用运行限制、环境变量与系统调用
+    ///
+    /// 其余资源限制可以继续通过 builder 覆盖，最后再通过 scale_time 应用时间倍数
+    pub fn runner(&self) -> Result<Sandbox> {
+        Ok(self.apply(Sandbox::new(self.run.clone())?, &self.run_limits))
+    }
+
+    /// 按 time_multiplier 放大时间限制
+    pub fn scale_time(&self, mut sandbox: Sandbox) -> Sandbox {
+        let scale = |limit: i32| (f64::from(limit) * self.time_multiplier).ceil() as i32;
+        sandbox.time_limit = sandbox.time_limit.map(scale);
+        sandbox.real_time_limit = sandbox.real_time_limit.map(scale);
+        sandbox
+    }
+
+    /// 运行时实际应用的资源限制，与 runner 经 limits 覆盖并应用时间倍数后的沙盒一致，无需创建沙盒
+    pub fn run_limits(&self, limits: Limits) -> Limits {
+        let preset = &self.run_limits;
+        let scale = |limit: i32| (f64::from(limit) * self.time_multiplier).ceil() as i32;
+        Limits {
+            time_limit: limits.time_limit.or(preset.time_limit).map(scale),
+            real_time_limit: limits.real_time_limit.or(preset.real_time_limit).map(scale),
+            memory_limit: limits.memory_limit.or(preset.memory_limit),
+            file_size_limit: limits.file_size_limit.or(preset.file_size_limit),
+            stdout_limit: limits.stdout_limit.or(preset.stdout_limit),
+            pids: limits.pids.or(preset.pids),
+            ..limits
+        }
+    }
+
+    fn apply(&self, mut sandbox: Sandbox, limits: &PresetLimits) -> Sandbox {
+        sandbox = sandbox
+            .time_limit(limits.time_limit.unwrap_or(0))
+            .real_time_limit(limits.real_time_limit.unwrap_or(0))
+            .memory_limit(limits.memory_limit.unwrap_or(0))
+            .file_size_limit(limits.file_size_limit.unwrap_or(0))
+            .stdout_limit(limits.stdout_limit.unwrap_or(0))
+            .pids(limits.pids.unwrap_or(0));
+        for (key, value) in &self.env {
+            sandbox = sandbox.env(key.clone(), value.clone());
+        }
+        for syscall in &self.syscalls {
+            sandbox = sandbox.allow_syscall(syscall.clone());
+        }
+        sandbox
+    }
+}
+
+/// 运行编译器，verdict 不为 Accepted 时即为编译失败
+pub fn compile(compiler: &mut Sandbox) -> Result<CompileStatus> {
+    let mut status = compiler.execute()?;
+    let mut message = status.stdout.take().unwrap_or_default();
+    message.extend(status.stderr.take().unwrap_or_default());
+    Ok(CompileStatus {
+        message: String::from_utf8_lossy(&message).trim().to_string(),
+        status,
+    })
+}
+
+/// 编译失败时的运行结果，程序没有运行，各项资源占用均为 0
+pub fn compile_error(compile: CompileStatus) -> RunnerStatus {
+    let mut status = RunnerStatus::new(0, &utils::new_rusage());
+    status.verdict = Verdict::CompileError;
+    status.compile = Some(Box::new(compile));
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(content: &str) -> String {
+        match Registry::parse(content) {
+            Err(Error::PresetError(e)) => e,
+            other => panic!("expected a preset error, got {:?}", other.map(|r| r.presets)),
+        }
+    }
+
+    #[test]
+    fn bundled_presets() {
+        let registry = Registry::parse(include_str!("../runtime/languages.toml")).unwrap();
+        assert!(registry.names().any(|name| name == "cpp"));
+        let rust = registry.get("rust").unwrap();
+        assert!(!rust.compile.as_ref().unwrap()[0].starts_with("/root/"));
+    }
+
+    #[test]
+    fn defaults() {
+        let registry = Registry::parse("[py]\nsource = \"main.py\"\nrun = [\"/usr/bin/python3\", \"main.py\"]\n").unwrap();
+        let preset = registry.get("py").unwrap();
+        assert_eq!(preset.compile, None);
+        assert_eq!(preset.time_multiplier, 1.0);
+        assert!(preset.env.is_empty() && preset.syscalls.is_empty());
+        assert_eq!(preset.run_limits.time_limit, None);
+    }
+
+    #[test]
+    fn unknown_language() {
+        let registry = Registry::parse("[b]\nsource = \"b\"\nrun = [\"/b\"]\n[a]\nsource = \"a\"\nrun = [\"/a\"]\n").unwrap();
+        match registry.get("c") {
+            Err(Error::PresetError(e)) => assert!(e.ends_with("available: a, b"), "{}", e),
+            _ => panic!("expected a preset error"),
+        }
+    }
+
+    #[test]
+    fn unknown_fields() {
+        parse_error("[c]\nsource = \"main.c\"\nrun = [\"/tmp/main\"]\ntimeout = 1\n");
+        parse_error("[c]\nsource = \"main.c\"\nrun = [\"/tmp/main\"]\n[c.run_limits]\nmemory = 1\n");
+        parse_error("[c]\nrun = [\"/tmp/main\"]\n");
+    }
+
+    #[test]
+    fn invalid_presets() {
+        let cases = [
+            ("run = []", "command must not be empty"),
+            ("run = [\"/a\"]\ncompile = []", "command must not be empty"),
+            ("run = [\"/a\"]\nsource = \"\"", "invalid source file name"),
+            ("run = [\"/a\"]\nsource = \"../main.c\"", "invalid source file name"),
+            ("run = [\"/a\"]\ntime_multiplier = 0.0", "invalid time multiplier"),
+            ("run = [\"/a\"]\ntime_multiplier = -1.0", "invalid time multiplier"),
+            ("run = [\"/a\"]\ntime_multiplier = nan", "invalid time multiplier"),
+            ("run = [\"/a\"]\nsyscalls = [\"read\"]", "syscall `read` is not denied by default"),
+            ("run = [\"/a\"]\nsyscalls = [\"no_such_call\"]", "is not denied by default"),
+        ];
+        for (preset, message) in &cases {
+            let source = if preset.contains("source") { "" } else { "source = \"main.c\"\n" };
+            let e = parse_error(&format!("[c]\n{}{}\n", source, preset));
+            assert!(e.starts_with("language `c`: ") && e.contains(message), "{}: {}", preset, e);
+        }
+    }
+
+    #[test]
+    fn denied_syscalls() {
+        let registry = Registry::parse("[c]\nsource = \"main.c\"\nrun = [\"/a\"]\nsyscalls = [\"personality\"]\n").unwrap();
+        assert_eq!(registry.get("c").unwrap().syscalls, ["personality"]);
+    }
+
+    #[test]
+    fn run_limits() {
+        let content = "[java]\nsource = \"Main.java\"\nrun = [\"/a\"]\ntime_multiplier = 1.5\n\
+                       [java.run_limits]\ntime_limit = 1000\nmemory_limit = 65536\n";
+        let registry = Registry::parse(content).unwrap();
+        let preset = registry.get("java").unwrap();
+        let limits = preset.run_limits(Limits::default());
+        assert_eq!(limits.time_limit, Some(1500));
+        assert_eq!(limits.memory_limit, Some(65536));
+        let limits = preset.run_limits(Limits {
+            time_limit: Some(3),
+            stderr_limit: Some(10),
+            ..Limits::default()
+        });
+        assert_eq!(limits.time_limit, Some(5));
+        assert_eq!(limits.memory_limit, Some(65536));
+        assert_eq!(limits.stderr_limit, Some(10));
+        assert_eq!(limits.real_time_limit, None);
+    }
+}
@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate log;
+
+mod utils;
+pub mod error;
+pub mod sandbox;
+mod runit;
+mod exec_args;
+pub mod status;
+pub mod seccomp;
+pub mod cgroups;
+pub mod output;
+pub mod interactive;
+pub mod checker;
+pub mod batch;
+pub mod language;
+pub mod mount;
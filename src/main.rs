@@ -1,23 +1,25 @@
 #[macro_use]
 extern crate log;
 
-use clap::{ArgSettings, Parser};
+use std::path::Path;
+use std::time::Instant;
+
+use clap::{ArgSettings, Parser, Subcommand};
 use env_logger::Builder;
 use log::LevelFilter;
 
-mod utils;
-mod error;
-mod sandbox;
-mod runit;
-mod exec_args;
-mod status;
-mod seccomp;
-mod cgroups;
+use newbie_sandbox::checker::CompareMode;
+use newbie_sandbox::error::Result;
+use newbie_sandbox::status::Verdict;
+use newbie_sandbox::{batch, cgroups, interactive, language, mount, sandbox, status};
 
 /// example: `newbie-sandbox -- /usr/bin/echo hello world`
 #[derive(Parser)]
 #[clap(version = "1.0", author = "MeiK <meik2333@gmail.com>")]
+#[clap(subcommand_negates_reqs = true)]
 struct Opts {
+    #[clap(subcommand)]
+    subcommand: Option<SubCommand>,
     /// 输入流，默认为 STDIN(0)
     #[clap(short, long, default_value = "/STDIN/")]
     input: String,
@@ -36,21 +38,128 @@ struct Opts {
     /// 运行结果输出位置，默认为 STDOUT(1)
     #[clap(short, long, default_value = "/STDOUT/")]
     result: String,
+    /// 运行结果的输出格式，可选 text、json 或 toml
+    #[clap(long, default_value = "text")]
+    result_format: status::ResultFormat,
     /// 运行 CPU 时间限制，单位 ms，默认无限制
     #[clap(short, long, default_value = "0")]
     time_limit: i32,
+    /// 运行真实时间限制，单位 ms，默认为 CPU 时间限制向上取整后再加 2s
+    #[clap(long, default_value = "0")]
+    real_time_limit: i32,
     /// 运行内存限制，单位 kib，默认无限制
     #[clap(short, long, default_value = "0")]
     memory_limit: i32,
-    /// 可写入的文件限制，单位 bit，默认无限制
+    /// 单个文件可写入的最大大小，单位 byte，默认无限制
     #[clap(short, long, default_value = "0")]
     file_size_limit: i32,
-    /// cgroup 版本，1 或 2
-    #[clap(short, long, default_value = "1")]
+    /// 标准输出的大小限制，单位 byte，超出时截断并判定为 OutputLimitExceeded，默认无限制
+    #[clap(long, default_value = "0")]
+    stdout_limit: u64,
+    /// 标准错误的大小限制，单位 byte，超出时截断并判定为 OutputLimitExceeded，默认无限制
+    #[clap(long, default_value = "0")]
+    stderr_limit: u64,
+    /// 工作目录的总磁盘占用限制，单位 byte，默认无限制
+    #[clap(long, default_value = "0")]
+    workdir_limit: u64,
+    /// cgroup 版本，1 或 2，默认根据 /sys/fs/cgroup 自动检测
+    #[clap(short, long, default_value = "0")]
     cgroup: i32,
+    /// 沙盒控制组的上级控制组，为相对于 cgroup 层级根目录的路径，默认为沙盒自身所在的控制组
+    #[clap(long, default_value = "")]
+    cgroup_parent: String,
     /// 最大可创建的 pid 数量，默认无限制
     #[clap(short, long, default_value = "0")]
     pids: i32,
+    /// CPU 配额，单位为核数，如 1.5 表示每 100ms 最多使用 150ms 的 CPU 时间，默认无限制
+    #[clap(long, default_value = "0")]
+    cpu_quota: f64,
+    /// 允许使用的 CPU 列表，如 `0-3,5`，默认无限制
+    #[clap(long, default_value = "")]
+    cpuset_cpus: String,
+    /// 允许使用的内存节点列表，如 `0`，默认无限制
+    #[clap(long, default_value = "")]
+    cpuset_mems: String,
+    /// 工作目录所在块设备的读取速率限制，单位 byte/s，默认无限制
+    #[clap(long, default_value = "0")]
+    io_read_bps: u64,
+    /// 工作目录所在块设备的写入速率限制，单位 byte/s，默认无限制
+    #[clap(long, default_value = "0")]
+    io_write_bps: u64,
+    /// 工作目录所在块设备每秒的读取次数限制，默认无限制
+    #[clap(long, default_value = "0")]
+    io_read_iops: u64,
+    /// 工作目录所在块设备每秒的写入次数限制，默认无限制
+    #[clap(long, default_value = "0")]
+    io_write_iops: u64,
+    /// 额外的绑定挂载，格式为 `外部路径:沙盒内路径[:ro|rw]`，默认只读，可以指定多次
+    #[clap(long, multiple_occurrences = true)]
+    bind: Vec<mount::Bind>,
+    /// 额外的 tmpfs，格式为 `沙盒内路径:大小`，大小单位为 byte，可以使用 k、m、g 后缀，如 `/dev/shm:64m`，可以指定多次
+    #[clap(long, multiple_occurrences = true)]
+    tmpfs: Vec<mount::Tmpfs>,
+    /// 以 rootfs 为只读的下层挂载 overlayfs，程序可以修改 rootfs，修改在运行结束后丢弃
+    #[clap(long)]
+    overlay: bool,
+    /// 指定时启用 overlayfs，并在运行结束后将修改复制到此目录中的 upper 目录以便检查
+    #[clap(long, default_value = "")]
+    overlay_dir: String,
+    /// overlayfs 上层目录所在 tmpfs 的大小，单位 byte，可以使用 k、m、g 后缀
+    #[clap(long, default_value = "64m", parse(try_from_str = mount::parse_size))]
+    overlay_size: u64,
+    /// 以此大小的 tmpfs 作为沙盒内部的 /tmp，不再挂载工作目录，单位 byte，可以使用 k、m、g 后缀，默认不使用
+    #[clap(long, default_value = "0", parse(try_from_str = mount::parse_size))]
+    workdir_size: u64,
+    /// tmpfs 工作目录的 inode 数量限制，默认无限制
+    #[clap(long, default_value = "0")]
+    workdir_inodes: u64,
+    /// 运行前复制到 tmpfs 工作目录中的文件，格式为 `外部路径:文件名`，可以指定多次
+    #[clap(long, multiple_occurrences = true)]
+    copy_in: Vec<mount::CopyFile>,
+    /// 运行后从 tmpfs 工作目录中复制出的文件，格式为 `外部路径:文件名`，可以指定多次
+    #[clap(long, multiple_occurrences = true)]
+    copy_out: Vec<mount::CopyFile>,
+    /// 复制出的单个文件的大小限制，单位 byte，可以使用 k、m、g 后缀，超出时不复制，默认与 --workdir-size 相同
+    #[clap(long, default_value = "0", parse(try_from_str = mount::parse_size))]
+    copy_out_limit: u64,
+    /// 标准答案文件，指定后程序正常结束时会将其输出与之比较，默认不比较
+    #[clap(long, default_value = "")]
+    answer: String,
+    /// 与答案的比较方式，可选 exact、token、line 或 float
+    #[clap(long, default_value = "line")]
+    compare: CompareMode,
+    /// float 比较方式允许的绝对误差
+    #[clap(long, default_value = "1e-6")]
+    abs_epsilon: f64,
+    /// float 比较方式允许的相对误差
+    #[clap(long, default_value = "1e-6")]
+    rel_epsilon: f64,
+    /// 特殊评测程序及其命令行参数（以空格分隔），以 `输入 输出 答案` 三个文件作为额外的参数调用，
+    /// 按 testlib 的退出码约定判定结果，默认按 --compare 比较
+    #[clap(long, default_value = "")]
+    checker: String,
+    /// 特殊评测程序的 CPU 时间限制，单位 ms
+    #[clap(long, default_value = "10000")]
+    checker_time_limit: i32,
+    /// 交互题的交互程序及其命令行参数（以空格分隔），与选手程序的标准输入输出交叉连接，
+    /// 资源限制由 --interactor-* 选项单独指定
+    #[clap(long, default_value = "")]
+    interactor: String,
+    /// 交互程序的 CPU 时间限制，单位 ms
+    #[clap(long, default_value = "10000")]
+    interactor_time_limit: i32,
+    /// 交互程序的真实时间限制，单位 ms，默认为 CPU 时间限制向上取整后再加 2s
+    #[clap(long, default_value = "0")]
+    interactor_real_time_limit: i32,
+    /// 交互程序的内存限制，单位 kib，默认无限制
+    #[clap(long, default_value = "0")]
+    interactor_memory_limit: i32,
+    /// 交互程序单个文件可写入的最大大小，单位 byte，默认无限制
+    #[clap(long, default_value = "0")]
+    interactor_file_size_limit: i32,
+    /// 交互程序最大可创建的 pid 数量，默认无限制
+    #[clap(long, default_value = "0")]
+    interactor_pids: i32,
     /// 要运行的程序及命令行参数
     #[clap(setting = ArgSettings::Last, required = true)]
     command: Vec<String>,
@@ -59,6 +168,233 @@ struct Opts {
     verbose: i32,
 }
 
+#[derive(Subcommand)]
+enum SubCommand {
+    /// 清理沙盒进程被 kill 后遗留的控制组，并结束其中仍在运行的进程
+    Gc,
+    /// 使用相同的资源限制依次运行多个测试点，输出每个测试点的结果与汇总结果
+    ///
+    /// 资源限制等选项需要写在 batch 之前，如 `newbie-sandbox -t 1000 batch ./tests -- /tmp/main`
+    Batch {
+        /// 测试点目录（`<name>.in` 与 `<name>.out` 或 `<name>.ans`），或每行为 `输入 [答案]` 的清单文件
+        tests: String,
+        /// 在第一个未通过的测试点后停止
+        #[clap(long)]
+        stop_on_failure: bool,
+        /// 要运行的程序及命令行参数
+        #[clap(setting = ArgSettings::Last, required = true)]
+        command: Vec<String>,
+    },
+    /// 按语言预设编译源代码，编译成功后在另一个沙盒中运行，编译失败时判定为 CompileError
+    ///
+    /// 资源限制等选项需要写在 judge 之前，如 `newbie-sandbox -t 1000 -i 1.in judge --language cpp main.cpp`
+    Judge {
+        /// 源代码文件
+        source: String,
+        /// 语言名称，即预设文件中的表名
+        #[clap(short, long)]
+        language: String,
+        /// 语言预设文件
+        #[clap(long, default_value = "./runtime/languages.toml")]
+        presets: String,
+        /// 测试点目录或清单文件，指定后同 batch 依次运行所有测试点，否则只运行一次
+        #[clap(long, default_value = "")]
+        tests: String,
+        /// 在第一个未通过的测试点后停止
+        #[clap(long)]
+        stop_on_failure: bool,
+    },
+}
+
+/// 将运行环境相关的选项应用到沙盒上
+fn environment(mut sandbox: sandbox::Sandbox, opts: &Opts) -> sandbox::Sandbox {
+    for bind in &opts.bind {
+        sandbox = sandbox.bind(bind.clone());
+    }
+    for tmpfs in &opts.tmpfs {
+        sandbox = sandbox.tmpfs(tmpfs.clone());
+    }
+    sandbox
+        .rootfs(opts.rootfs.clone())
+        .overlay(opts.overlay)
+        .overlay_dir(opts.overlay_dir.clone())
+        .overlay_size(opts.overlay_size)
+        .cgroup(opts.cgroup)
+        .cgroup_parent(opts.cgroup_parent.clone())
+}
+
+/// 将资源限制与运行环境相关的选项应用到沙盒上
+fn configure(sandbox: sandbox::Sandbox, opts: &Opts) -> sandbox::Sandbox {
+    environment(sandbox, opts)
+        .time_limit(opts.time_limit)
+        .real_time_limit(opts.real_time_limit)
+        .memory_limit(opts.memory_limit)
+        .file_size_limit(opts.file_size_limit)
+        .stdout_limit(opts.stdout_limit)
+        .stderr_limit(opts.stderr_limit)
+        .workdir_limit(opts.workdir_limit)
+        .pids(opts.pids)
+        .cpu_quota(opts.cpu_quota)
+        .cpuset_cpus(opts.cpuset_cpus.clone())
+        .cpuset_mems(opts.cpuset_mems.clone())
+        .io_read_bps(opts.io_read_bps)
+        .io_write_bps(opts.io_write_bps)
+        .io_read_iops(opts.io_read_iops)
+        .io_write_iops(opts.io_write_iops)
+        .workdir(opts.workdir.clone())
+}
+
+/// 在资源限制之外，应用与答案比较相关的选项
+fn contestant(sandbox: sandbox::Sandbox, opts: &Opts) -> Result<sandbox::Sandbox> {
+    let compare = match opts.compare {
+        CompareMode::Float { .. } => CompareMode::Float {
+            absolute: opts.abs_epsilon,
+            relative: opts.rel_epsilon,
+        },
+        mode => mode,
+    };
+    let mut contestant = configure(sandbox, opts)
+        .workdir_size(opts.workdir_size)
+        .workdir_inodes(opts.workdir_inodes)
+        .copy_out_limit(opts.copy_out_limit)
+        .answer(opts.answer.clone())
+        .compare_mode(compare);
+    for file in &opts.copy_in {
+        contestant = contestant.copy_in(file.clone());
+    }
+    for file in &opts.copy_out {
+        contestant = contestant.copy_out(file.clone());
+    }
+    if opts.checker.is_empty() {
+        return Ok(contestant);
+    }
+    let command = opts.checker.split_whitespace().map(String::from).collect();
+    let checker = environment(sandbox::Sandbox::new(command)?, opts).time_limit(opts.checker_time_limit);
+    Ok(contestant.checker(checker))
+}
+
+/// 交互程序的沙盒，资源限制与选手程序相互独立，运行环境相同
+fn interactor(opts: &Opts) -> Result<sandbox::Sandbox> {
+    let command = opts.interactor.split_whitespace().map(String::from).collect();
+    Ok(environment(sandbox::Sandbox::new(command)?, opts)
+        .time_limit(opts.interactor_time_limit)
+        .real_time_limit(opts.interactor_real_time_limit)
+        .memory_limit(opts.interactor_memory_limit)
+        .file_size_limit(opts.interactor_file_size_limit)
+        .pids(opts.interactor_pids)
+        .workdir(opts.workdir.clone()))
+}
+
+fn run_batch(opts: &Opts, tests: &str, stop_on_failure: bool, command: &[String]) {
+    if !opts.interactor.is_empty() {
+        error!("batch mode does not support --interactor");
+        std::process::exit(1);
+    }
+    let result = batch::load(Path::new(tests))
+        .and_then(|cases| batch::run(&cases, || contestant(sandbox::Sandbox::new(command.to_vec())?, opts), stop_on_failure));
+    let status = match result {
+        Ok(status) => status,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = write_batch_result(&status, opts, &limits(opts)) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    debug!("verdict     = {}", status.verdict);
+    debug!("passed      = {}/{}", status.passed, status.total);
+}
+
+/// 编译后运行一次或依次运行所有测试点
+fn run_judge(opts: &Opts, source: &str, language: &str, presets: &str, tests: &str, stop_on_failure: bool) -> Result<()> {
+    let registry = language::Registry::load(Path::new(presets))?;
+    let preset = registry.get(language)?;
+    let workspace = preset.workspace(Path::new(source))?;
+    let workdir = workspace.path().to_string_lossy().to_string();
+    let start = Instant::now();
+    let compile = match preset.compiler()? {
+        Some(compiler) => {
+            let mut compiler = environment(compiler, opts).workdir(workdir.clone());
+            let compile = language::compile(&mut compiler)?;
+            debug!("compile     = {}", compile.status.verdict);
+            Some(compile)
+        }
+        None => None,
+    };
+    let compile_failed = matches!(&compile, Some(compile) if compile.status.verdict != Verdict::Accepted);
+    // 命令行中的资源限制覆盖预设的默认值，之后再应用时间倍数
+    let runner = || Ok(preset.scale_time(contestant(preset.runner()?, opts)?.workdir(workdir.clone())));
+
+    if tests.is_empty() {
+        let mut contestant = runner()?
+            .stdin(opts.input.clone())
+            .stdout(opts.output.clone())
+            .stderr(opts.error.clone())
+            .result(opts.result.clone())?
+            .result_format(opts.result_format);
+        let status = match compile {
+            Some(compile) if compile_failed => language::compile_error(compile),
+            compile => {
+                let mut status = contestant.judge()?;
+                status.compile = compile.map(Box::new);
+                status
+            }
+        };
+        debug!("verdict     = {}", status.verdict);
+        return contestant.write_result(&status, start);
+    }
+
+    let cases = batch::load(Path::new(tests))?;
+    let status = match compile {
+        Some(compile) if compile_failed => batch::BatchStatus::compile_error(&cases, compile),
+        compile => {
+            let mut status = batch::run(&cases, runner, stop_on_failure)?;
+            status.compile = compile.map(Box::new);
+            status
+        }
+    };
+    debug!("verdict     = {}", status.verdict);
+    write_batch_result(&status, opts, &preset.run_limits(limits(opts)))
+}
+
+/// 将批量运行的结果输出到 --result 指定的位置
+fn write_batch_result(status: &batch::BatchStatus, opts: &Opts, limits: &status::Limits) -> Result<()> {
+    let fd = sandbox::open_result(&opts.result)?;
+    let result = status.result_to_fd(fd, opts.result_format, limits);
+    if fd != 1 {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    result
+}
+
+/// 命令行中的资源限制，与 configure 应用到沙盒的限制一致
+fn limits(opts: &Opts) -> status::Limits {
+    let set = |l: i32| Some(l).filter(|l| *l != 0);
+    let positive = |l: u64| Some(l).filter(|l| *l > 0);
+    let text = |s: &String| Some(s.clone()).filter(|s| !s.is_empty());
+    status::Limits {
+        time_limit: set(opts.time_limit),
+        real_time_limit: set(opts.real_time_limit),
+        memory_limit: set(opts.memory_limit),
+        file_size_limit: set(opts.file_size_limit),
+        stdout_limit: positive(opts.stdout_limit),
+        stderr_limit: positive(opts.stderr_limit),
+        workdir_limit: positive(opts.workdir_limit),
+        pids: Some(opts.pids).filter(|l| *l > 0),
+        cpu_quota: Some(opts.cpu_quota).filter(|l| *l > 0.0),
+        cpuset_cpus: text(&opts.cpuset_cpus),
+        cpuset_mems: text(&opts.cpuset_mems),
+        io_read_bps: positive(opts.io_read_bps),
+        io_write_bps: positive(opts.io_write_bps),
+        io_read_iops: positive(opts.io_read_iops),
+        io_write_iops: positive(opts.io_write_iops),
+    }
+}
+
 fn main() {
     let opts: Opts = Opts::parse();
 
@@ -69,25 +405,88 @@ fn main() {
     };
     Builder::new().filter_level(log_level).init();
 
-    let status = sandbox::Sandbox::new(opts.command)
-        .rootfs(opts.rootfs)
-        .stdin(opts.input)
-        .stdout(opts.output)
-        .stderr(opts.error)
-        .time_limit(opts.time_limit)
-        .memory_limit(opts.memory_limit)
-        .file_size_limit(opts.file_size_limit)
-        .cgroup(opts.cgroup)
-        .pids(opts.pids)
-        .workdir(opts.workdir)
-        .result(opts.result)
-        .run();
+    if let Some(SubCommand::Gc) = opts.subcommand {
+        match cgroups::gc(opts.cgroup) {
+            Ok(count) => info!("removed {} orphaned cgroups", count),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(SubCommand::Batch {
+        tests,
+        stop_on_failure,
+        command,
+    }) = &opts.subcommand
+    {
+        run_batch(&opts, tests, *stop_on_failure, command);
+        return;
+    }
+
+    if let Some(SubCommand::Judge {
+        source,
+        language,
+        presets,
+        tests,
+        stop_on_failure,
+    }) = &opts.subcommand
+    {
+        if !opts.interactor.is_empty() {
+            error!("judge mode does not support --interactor");
+            std::process::exit(1);
+        }
+        // 编译产物位于外部的临时工作目录中，tmpfs 会将其覆盖
+        if opts.workdir_size > 0 {
+            error!("judge mode does not support --workdir-size");
+            std::process::exit(1);
+        }
+        if let Err(e) = run_judge(&opts, source, language, presets, tests, *stop_on_failure) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // 交互题的结果由交互程序决定，选手程序的输出经由管道交给交互程序，不会与答案比较
+    if !opts.interactor.is_empty() && !(opts.answer.is_empty() && opts.checker.is_empty()) {
+        error!("interactive mode does not support --answer or --checker");
+        std::process::exit(1);
+    }
+    let contestant = sandbox::Sandbox::new(opts.command.clone()).and_then(|sandbox| {
+        contestant(sandbox, &opts)?
+            .stdin(opts.input.clone())
+            .stdout(opts.output.clone())
+            .stderr(opts.error.clone())
+            .result(opts.result.clone())
+    });
+    let status = contestant.and_then(|contestant| {
+        let mut contestant = contestant.result_format(opts.result_format);
+        if opts.interactor.is_empty() {
+            contestant.run()
+        } else {
+            interactive::run(&mut contestant, &mut interactor(&opts)?)
+        }
+    });
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
 
-    // 此处获取的数值为沙盒的总资源用量，我们指定进程的资源占用需要用 result 获取
-    debug!("sandbox time used   = {}", status.time_used);
-    debug!("sandbox memory used = {}", status.memory_used);
-    debug!("sandbox exit_code   = {}", status.exit_code);
-    debug!("sandbox status      = {}", status.status);
-    debug!("sandbox signal      = {}", status.signal);
+    // 此处获取的数值即为我们指定进程的资源占用
+    debug!("time used   = {}", status.time_used);
+    debug!("real time   = {}", status.real_time_used);
+    debug!("memory used = {}", status.memory_used);
+    debug!("io read     = {}", status.io_read_bytes);
+    debug!("io write    = {}", status.io_write_bytes);
+    debug!("exit_code   = {}", status.exit_code);
+    debug!("status      = {}", status.status);
+    debug!("signal      = {}", status.signal);
+    debug!("verdict     = {}", status.verdict);
 }
@@ -0,0 +1,722 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path};
+use std::ptr;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+/// 将外部的文件或目录绑定挂载到沙盒内部
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bind {
+    /// 外部的路径
+    pub source: String,
+    /// 沙盒内部的绝对路径
+    pub target: String,
+    /// 是否只读，两种挂载都会禁止 suid 与设备文件
+    pub read_only: bool,
+}
+
+impl Bind {
+    pub fn read_only(source: String, target: String) -> Self {
+        Bind {
+            source,
+            target,
+            read_only: true,
+        }
+    }
+
+    pub fn read_write(source: String, target: String) -> Self {
+        Bind {
+            source,
+            target,
+            read_only: false,
+        }
+    }
+
+    /// 检查外部路径是否存在，以及内部路径是否为不含 `..` 的绝对路径
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !Path::new(&self.source).exists() {
+            return Err(Error::MountError(format!("{} does not exist", self.source)));
+        }
+        validate_target(&self.target)
+    }
+}
+
+fn validate_target(target: &str) -> Result<()> {
+    let path = Path::new(target);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::MountError(format!("{} must be an absolute path without `..`", target)));
+    }
+    Ok(())
+}
+
+/// 格式为 `host:sandbox[:ro|rw]`，默认只读
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let read_only = match parts.get(2) {
+            None | Some(&"ro") => true,
+            Some(&"rw") => false,
+            Some(mode) => return Err(format!("unknown bind mode `{}`, expected ro or rw", mode)),
+        };
+        match parts[..] {
+            [source, target, ..] if parts.len() <= 3 && !source.is_empty() && !target.is_empty() => Ok(Bind {
+                source: source.to_string(),
+                target: target.to_string(),
+                read_only,
+            }),
+            _ => Err(format!("invalid bind `{}`, expected host:sandbox[:ro|rw]", s)),
+        }
+    }
+}
+
+/// 挂载到沙盒内部的 tmpfs，如 `/dev/shm`、`/var/tmp`，占用的内存计入沙盒的内存使用
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tmpfs {
+    /// 沙盒内部的绝对路径
+    pub target: String,
+    /// 大小上限，单位 byte
+    pub size: u64,
+}
+
+impl Tmpfs {
+    pub fn new(target: String, size: u64) -> Self {
+        Tmpfs { target, size }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.size == 0 {
+            return Err(Error::MountError(format!("size of tmpfs {} must not be 0", self.target)));
+        }
+        validate_target(&self.target)
+    }
+}
+
+/// 格式为 `sandbox:size`，size 单位为 byte，可以使用 k、m、g 后缀，如 `/dev/shm:64m`
+impl FromStr for Tmpfs {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (target, size) = match s.split_once(':') {
+            Some((target, size)) if !target.is_empty() => (target, size.to_ascii_lowercase()),
+            _ => return Err(format!("invalid tmpfs `{}`, expected sandbox:size", s)),
+        };
+        match parse_size(&size) {
+            Ok(size) if size > 0 => Ok(Tmpfs::new(target.to_string(), size)),
+            _ => Err(format!("invalid tmpfs size `{}`", size)),
+        }
+    }
+}
+
+/// 解析以 byte 为单位的大小，可以使用 k、m、g 后缀（大小写均可），如 `64m`
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let size = s.to_ascii_lowercase();
+    let (number, unit) = match size.chars().last() {
+        Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (&size[..], 1),
+    };
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit)) {
+        Some(size) => Ok(size),
+        None => Err(format!("invalid size `{}`", s)),
+    }
+}
+
+/// 在外部与 tmpfs 工作目录之间复制的文件
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyFile {
+    /// 外部的路径
+    pub host: String,
+    /// 沙盒内部 /tmp 中的文件名，不能包含 `/`
+    pub name: String,
+}
+
+impl CopyFile {
+    pub fn new(host: String, name: String) -> Self {
+        CopyFile { host, name }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.contains('/') || self.name == "." || self.name == ".." {
+            return Err(Error::MountError(format!("invalid file name `{}` in workdir", self.name)));
+        }
+        Ok(())
+    }
+}
+
+/// 格式为 `host:name`，复制到沙盒内与从沙盒内复制出时相同
+impl FromStr for CopyFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((host, name)) if !host.is_empty() && !name.is_empty() => {
+                Ok(CopyFile::new(host.to_string(), name.to_string()))
+            }
+            _ => Err(format!("invalid file `{}`, expected host:name", s)),
+        }
+    }
+}
+
+/// rootfs 中存在时替换为外部设备文件的设备
+const DEVICES: [&str; 4] = ["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"];
+
+/// openat2 的参数，libc 中的定义无法在外部构造
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// rootfs 中的挂载点
+///
+/// 以 rootfs 为根解析路径，其中的符号链接与 `..` 都不会离开 rootfs，挂载时经由 /proc/self/fd 使用解析得到的描述符
+pub(crate) struct MountPoint {
+    fd: i32,
+}
+
+impl MountPoint {
+    pub(crate) fn open(rootfs: &str, target: &str) -> io::Result<Self> {
+        Self::open_with(rootfs, target, 0)
+    }
+
+    /// flags 为 O_PATH 之外额外的选项，如 O_NOFOLLOW
+    fn open_with(rootfs: &str, target: &str, flags: i32) -> io::Result<Self> {
+        let root = fs::File::open(rootfs)?;
+        let path = match target.trim_start_matches('/') {
+            "" => CString::new("."),
+            path => CString::new(path),
+        };
+        let path = path.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let how = OpenHow {
+            flags: (libc::O_PATH | libc::O_CLOEXEC | flags) as u64,
+            mode: 0,
+            resolve: libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                root.as_raw_fd(),
+                path.as_ptr(),
+                &how as *const OpenHow,
+                mem::size_of::<OpenHow>(),
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(MountPoint { fd: fd as i32 })
+    }
+
+    /// 打开挂载点，不存在时在 rootfs 中逐级创建，dir 为 false 时最后一级创建为空文件
+    ///
+    /// 新建的目录与文件相对于已解析的上级目录的描述符创建，同样不会离开 rootfs
+    pub(crate) fn create(rootfs: &str, target: &str, dir: bool) -> io::Result<Self> {
+        match Self::open(rootfs, target) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => return result,
+        }
+        let path = Path::new(target);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (Self::create(rootfs, &parent.to_string_lossy(), true)?, name),
+            _ => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+        let name = CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ret = unsafe {
+            if dir {
+                libc::mkdirat(parent.fd, name.as_ptr(), 0o755)
+            } else {
+                let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+                let fd = libc::openat(parent.fd, name.as_ptr(), flags, 0o644);
+                if fd >= 0 {
+                    libc::close(fd);
+                }
+                fd
+            }
+        };
+        // 已经存在但无法解析的只可能是指向 rootfs 之外的符号链接，重新打开时同样会失败
+        if ret < 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST) {
+            return Err(io::Error::last_os_error());
+        }
+        Self::open(rootfs, target)
+    }
+
+    /// 在沙盒内部打开挂载点，失败时只能以 SystemError 结束
+    unsafe fn open_or_panic(rootfs: &str, target: &str) -> Self {
+        match Self::open(rootfs, target) {
+            Ok(mount_point) => mount_point,
+            Err(e) => panic!("mount point {} in rootfs: {}", target, e),
+        }
+    }
+
+    unsafe fn create_or_panic(rootfs: &str, target: &str, dir: bool) -> Self {
+        match Self::create(rootfs, target, dir) {
+            Ok(mount_point) => mount_point,
+            Err(e) => panic!("mount point {} in rootfs: {}", target, e),
+        }
+    }
+
+    fn is_dir(&self) -> io::Result<bool> {
+        let mut stat = unsafe { mem::zeroed::<libc::stat>() };
+        if unsafe { libc::fstat(self.fd, &mut stat) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat.st_mode & libc::S_IFMT == libc::S_IFDIR)
+    }
+
+    fn path(&self) -> String {
+        format!("/proc/self/fd/{}", self.fd)
+    }
+}
+
+impl Drop for MountPoint {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// 检查挂载点能否在 root 中创建，需要在创建沙盒前调用，dir 为挂载点是否应为目录
+///
+/// 只检查而不创建：挂载点已经存在时类型需要与 dir 一致，否则最近的已存在的上级需要为目录。
+/// 挂载点在挂载时才会创建，overlay 模式下新建的挂载点只会出现在上层目录中
+pub(crate) fn validate_mount_point(root: &str, target: &str, dir: bool) -> Result<()> {
+    let error = |reason: String| Err(Error::MountError(format!("mount point {} in {}: {}", target, root, reason)));
+    for (depth, path) in Path::new(target).ancestors().enumerate() {
+        let mount_point = match MountPoint::open(root, &path.to_string_lossy()) {
+            Ok(mount_point) => mount_point,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // 无法解析的符号链接不能被替换为新建的挂载点
+                if MountPoint::open_with(root, &path.to_string_lossy(), libc::O_NOFOLLOW).is_ok() {
+                    return error(format!("{} is a dangling symbolic link", path.display()));
+                }
+                continue;
+            }
+            Err(e) => return error(e.to_string()),
+        };
+        let is_dir = try_io!(mount_point.is_dir());
+        if depth == 0 && is_dir != dir {
+            return error(format!("expected {}", if dir { "a directory" } else { "a file" }));
+        }
+        if depth > 0 && !is_dir {
+            return error(format!("{} is not a directory", path.display()));
+        }
+        return Ok(());
+    }
+    error(String::from("no such file or directory"))
+}
+
+/// 将 rootfs 绑定挂载到自身，使其成为独立的挂载点，之后的挂载都位于其上
+///
+/// 需要在其他挂载之前调用，并在所有挂载完成后通过 remount_root_read_only 将其重新挂载为只读。
+/// 不使用 MS_REC，rootfs 之下已有的其他挂载不会出现在沙盒中，否则只读的重新挂载不会作用于它们
+pub(crate) unsafe fn bind_root(rootfs: &str) {
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!(rootfs),
+        c_str_ptr!(rootfs),
+        c_str_ptr!("none"),
+        libc::MS_BIND,
+        ptr::null_mut(),
+    ));
+}
+
+/// 以 rootfs 为只读的下层、layers 中的 upper 为上层，在 rootfs 上挂载 overlayfs，替代 bind_root
+///
+/// 程序可以在文件权限允许的范围内修改 rootfs，修改只会写入上层目录，不会改变 rootfs 本身
+pub(crate) unsafe fn overlay(rootfs: &str, layers: &str) {
+    let options = format!("lowerdir={},upperdir={}/upper,workdir={}/work", rootfs, layers, layers);
+    trace!("overlay {}", options);
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!("overlay"),
+        c_str_ptr!(rootfs),
+        c_str_ptr!("overlay"),
+        libc::MS_NOSUID | libc::MS_NODEV,
+        c_str_ptr!(options) as *const libc::c_void,
+    ));
+}
+
+/// 检查 overlayfs 的路径，并清空 dir 中上一次运行保留的 `upper` 目录，需要在创建沙盒前调用
+///
+/// dir 为保留修改的目录，不能位于 rootfs 之中，也不能包含 rootfs
+pub(crate) fn prepare_overlay(rootfs: &str, layers: &str, dir: Option<&str>) -> Result<()> {
+    // overlayfs 的挂载参数以 `,` 与 `:` 分隔
+    for path in [rootfs, layers].iter() {
+        if path.contains(',') || path.contains(':') {
+            return Err(Error::MountError(format!("overlay path {} must not contain `,` or `:`", path)));
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    // 目录可能尚不存在，从已存在的上级目录开始解析，检查通过后再创建
+    let path = Path::new(dir);
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::MountError(format!("overlay dir {} must not contain `..`", dir)));
+    }
+    // 相对路径的最后一级上级目录为空路径，即当前目录
+    let existing = path.ancestors().find(|p| p.as_os_str().is_empty() || p.exists()).unwrap();
+    let base = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+    let canonical = try_io!(fs::canonicalize(base)).join(path.strip_prefix(existing).unwrap());
+    let root = try_io!(fs::canonicalize(rootfs));
+    if canonical.starts_with(&root) || root.starts_with(&canonical) {
+        return Err(Error::MountError(format!("overlay dir {} must be outside of rootfs {}", dir, rootfs)));
+    }
+    try_io!(fs::create_dir_all(&canonical));
+    let upper = canonical.join("upper");
+    if fs::symlink_metadata(&upper).is_ok() {
+        try_io!(fs::remove_dir_all(&upper));
+    }
+    Ok(())
+}
+
+/// 在外部的挂载点 layers 上挂载限制大小的 tmpfs，并在其中创建上层目录 `upper` 与其工作目录 `work`
+///
+/// 上层目录的修改占用的是内存，计入沙盒的内存使用，不会写满外部的磁盘。
+/// 上层目录的权限与 rootfs 相同，否则挂载后的根目录会继承新建目录的权限
+pub(crate) unsafe fn overlay_layers(rootfs: &str, layers: &str, size: u64) {
+    mount_tmpfs(layers, &format!("size={},mode=0700", size));
+    let mode = fs::metadata(rootfs).unwrap().permissions().mode();
+    for name in ["upper", "work"].iter() {
+        fs::create_dir(Path::new(layers).join(name)).unwrap();
+    }
+    fs::set_permissions(Path::new(layers).join("upper"), fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// 将上层目录中的修改复制到外部的 `dir/upper` 中以便检查，需要在沙盒内的进程全部结束后调用
+///
+/// 只复制目录、普通文件与符号链接，符号链接不会被跟随，表示删除的 whiteout 等特殊文件会被跳过。
+/// 复制出的文件属于 root，权限统一为 0644，程序无法借此在外部留下 suid 文件
+pub(crate) fn save_upper(layers: &str, dir: &str) -> io::Result<()> {
+    // 目录层级可能很深，不使用递归，以免耗尽沙盒内部的栈
+    let mut dirs = vec![(Path::new(layers).join("upper"), Path::new(dir).join("upper"))];
+    while let Some((source, target)) = dirs.pop() {
+        fs::create_dir(&target)?;
+        for entry in fs::read_dir(&source)? {
+            let entry = entry?;
+            let (from, to) = (entry.path(), target.join(entry.file_name()));
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push((from, to));
+            } else if file_type.is_file() {
+                // 即使文件在检查后被替换，也不会跟随符号链接或阻塞在命名管道上
+                let mut source = fs::OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+                    .open(&from)?;
+                if !source.metadata()?.is_file() {
+                    continue;
+                }
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o644).open(&to)?;
+                io::copy(&mut source, &mut file)?;
+            } else if file_type.is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 将外部的常用设备绑定挂载到 rootfs 中同名的设备上
+///
+/// rootfs 的挂载禁止了其中的设备文件，绑定挂载的设备不受此影响
+pub(crate) unsafe fn bind_devices(rootfs: &str) {
+    for device in DEVICES.iter() {
+        let target = match MountPoint::open(rootfs, device) {
+            Ok(target) if Path::new(device).exists() => target,
+            _ => continue,
+        };
+        syscall_or_panic!(libc::mount(
+            c_str_ptr!(*device),
+            c_str_ptr!(target.path()),
+            c_str_ptr!("none"),
+            libc::MS_BIND,
+            ptr::null_mut(),
+        ));
+    }
+}
+
+/// 在 rootfs 中挂载 proc
+pub(crate) unsafe fn proc(rootfs: &str) {
+    let target = MountPoint::create_or_panic(rootfs, "/proc", true);
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!("proc"),
+        c_str_ptr!(target.path()),
+        c_str_ptr!("proc"),
+        0,
+        ptr::null_mut(),
+    ));
+}
+
+/// 将 rootfs 重新挂载为只读，此后除工作目录与其他可写挂载外，程序无法修改任何文件
+pub(crate) unsafe fn remount_root_read_only(rootfs: &str) {
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!("none"),
+        c_str_ptr!(rootfs),
+        c_str_ptr!("none"),
+        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+        ptr::null_mut(),
+    ));
+}
+
+/// 在 rootfs 中挂载 tmpfs，挂载点不存在时创建，需要在 pivot_root 之前调用
+pub(crate) unsafe fn tmpfs(rootfs: &str, tmpfs: &Tmpfs) {
+    let target = MountPoint::create_or_panic(rootfs, &tmpfs.target, true);
+    // 1777 使 nobody 可以写入，同时只能删除自己的文件
+    mount_tmpfs(&target.path(), &format!("size={},mode=1777", tmpfs.size));
+}
+
+/// overlayfs 上层 tmpfs 默认的大小上限，单位 byte
+pub const DEFAULT_OVERLAY_SIZE: u64 = 64 << 20;
+
+/// 在外部的挂载点 target 上挂载 tmpfs，并将 files 复制到其中，之后再作为沙盒内部的 /tmp 挂载
+///
+/// 复制的文件属于 nobody，程序可以修改或删除它们，inodes 为 0 时不限制 inode 数量
+pub(crate) unsafe fn tmpfs_workdir(target: &str, size: u64, inodes: u64, files: &[CopyFile]) {
+    mount_tmpfs(target, &format!("size={},nr_inodes={},mode=1777", size, inodes));
+    for file in files {
+        let path = format!("{}/{}", target, file.name);
+        trace!("copy {} -> {}", file.host, path);
+        fs::copy(&file.host, &path).unwrap();
+        syscall_or_panic!(libc::chown(c_str_ptr!(path), 65534, 65534));
+    }
+}
+
+unsafe fn mount_tmpfs(target: &str, options: &str) {
+    trace!("tmpfs {} {}", target, options);
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!("tmpfs"),
+        c_str_ptr!(target),
+        c_str_ptr!("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV,
+        c_str_ptr!(options) as *const libc::c_void,
+    ));
+}
+
+/// 在 rootfs 中进行绑定挂载，挂载点不存在时创建，需要在 pivot_root 之前调用
+///
+/// 不使用 MS_REC，source 之下的其他挂载不会出现在沙盒中，只读挂载因此不会遗留可写的子挂载。
+/// 挂载的选项需要先绑定再重新挂载才会生效，可写的挂载同样禁止 suid 与设备文件
+pub(crate) unsafe fn bind(rootfs: &str, bind: &Bind) {
+    trace!("bind {} -> {}", bind.source, bind.target);
+    let target = MountPoint::create_or_panic(rootfs, &bind.target, Path::new(&bind.source).is_dir());
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!(bind.source.clone()),
+        c_str_ptr!(target.path()),
+        c_str_ptr!("none"),
+        libc::MS_BIND,
+        ptr::null_mut(),
+    ));
+    // 之前的描述符指向被覆盖的挂载点，重新解析才能得到新的挂载
+    let target = MountPoint::open_or_panic(rootfs, &bind.target);
+    let read_only = if bind.read_only { libc::MS_RDONLY } else { 0 };
+    syscall_or_panic!(libc::mount(
+        c_str_ptr!("none"),
+        c_str_ptr!(target.path()),
+        c_str_ptr!("none"),
+        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_NOSUID | libc::MS_NODEV | read_only,
+        ptr::null_mut(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 返回 MountError 的错误信息，其他结果均视为失败
+    fn mount_error(result: Result<()>) -> String {
+        match result {
+            Err(Error::MountError(message)) => message,
+            result => panic!("expected MountError, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_bind() {
+        assert_eq!("/data:/data".parse(), Ok(Bind::read_only("/data".into(), "/data".into())));
+        assert_eq!("/data:/mnt:ro".parse(), Ok(Bind::read_only("/data".into(), "/mnt".into())));
+        assert_eq!("/data:/mnt:rw".parse(), Ok(Bind::read_write("/data".into(), "/mnt".into())));
+
+        assert_eq!("/data:/mnt:RW".parse::<Bind>(), Err(String::from("unknown bind mode `RW`, expected ro or rw")));
+        assert_eq!("/data:/mnt:".parse::<Bind>(), Err(String::from("unknown bind mode ``, expected ro or rw")));
+        for s in &["/data:/mnt:rw:ro", "/data", "/data:", ":/data", ""] {
+            assert_eq!(s.parse::<Bind>(), Err(format!("invalid bind `{}`, expected host:sandbox[:ro|rw]", s)));
+        }
+    }
+
+    #[test]
+    fn validate_bind() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().to_string_lossy().to_string();
+        assert!(Bind::read_only(source.clone(), "/data".into()).validate().is_ok());
+        assert!(Bind::read_write(source.clone(), "/usr/./lib".into()).validate().is_ok());
+
+        let missing = dir.path().join("missing").to_string_lossy().to_string();
+        let message = mount_error(Bind::read_only(missing.clone(), "/data".into()).validate());
+        assert_eq!(message, format!("{} does not exist", missing));
+        let message = mount_error(Bind::read_only(source.clone(), "data".into()).validate());
+        assert_eq!(message, "data must be an absolute path without `..`");
+        // 内部路径不能通过 `..` 逃逸到挂载点之外
+        let message = mount_error(Bind::read_write(source.clone(), "/tmp/../etc".into()).validate());
+        assert_eq!(message, "/tmp/../etc must be an absolute path without `..`");
+        mount_error(Bind::read_write(source, "/..".into()).validate());
+    }
+
+    #[test]
+    fn parse_tmpfs() {
+        assert_eq!("/dev/shm:64m".parse(), Ok(Tmpfs::new("/dev/shm".into(), 64 << 20)));
+        assert_eq!("/var/tmp:16K".parse(), Ok(Tmpfs::new("/var/tmp".into(), 16 << 10)));
+        assert_eq!("/run:4096".parse(), Ok(Tmpfs::new("/run".into(), 4096)));
+
+        for s in &["/run", ":64m", ""] {
+            assert_eq!(s.parse::<Tmpfs>(), Err(format!("invalid tmpfs `{}`, expected sandbox:size", s)));
+        }
+        // 大小在转为小写后报告
+        for (s, size) in &[("/run:0", "0"), ("/run:0M", "0m"), ("/run:", ""), ("/run:m", "m"), ("/run:-1", "-1")] {
+            assert_eq!(s.parse::<Tmpfs>(), Err(format!("invalid tmpfs size `{}`", size)));
+        }
+        assert_eq!("/run:1t".parse::<Tmpfs>(), Err(String::from("invalid tmpfs size `1t`")));
+        assert_eq!("/run:64m:rw".parse::<Tmpfs>(), Err(String::from("invalid tmpfs size `64m:rw`")));
+    }
+
+    #[test]
+    fn validate_tmpfs() {
+        assert!(Tmpfs::new("/dev/shm".into(), 1).validate().is_ok());
+        assert_eq!(mount_error(Tmpfs::new("/run".into(), 0).validate()), "size of tmpfs /run must not be 0");
+        assert_eq!(mount_error(Tmpfs::new("run".into(), 1).validate()), "run must be an absolute path without `..`");
+        let message = mount_error(Tmpfs::new("/dev/shm/../../etc".into(), 1).validate());
+        assert_eq!(message, "/dev/shm/../../etc must be an absolute path without `..`");
+    }
+
+    #[test]
+    fn overlay_dir_outside_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs).unwrap();
+        let rootfs = rootfs.to_str().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        for overlay_dir in &[path("rootfs"), path("rootfs/upper"), path("rootfs/a/b"), path(""), path("a/../rootfs")] {
+            assert!(prepare_overlay(rootfs, "/tmp/layers", Some(overlay_dir)).is_err(), "{}", overlay_dir);
+        }
+        assert!(!Path::new(&path("rootfs/a")).exists());
+        assert!(prepare_overlay(rootfs, "/tmp/layers,x", None).is_err());
+
+        fs::create_dir_all(path("overlay/upper/etc")).unwrap();
+        prepare_overlay(rootfs, "/tmp/layers", Some(&path("overlay"))).unwrap();
+        assert!(Path::new(&path("overlay")).is_dir());
+        assert!(!Path::new(&path("overlay/upper")).exists());
+    }
+
+    #[test]
+    fn sizes() {
+        let cases = [
+            ("0", Some(0)),
+            ("4096", Some(4096)),
+            ("1k", Some(1 << 10)),
+            ("64M", Some(64 << 20)),
+            ("2g", Some(2 << 30)),
+            ("17179869183g", Some(17179869183 << 30)),
+            ("17179869184g", None),
+            ("18446744073709551615", Some(u64::MAX)),
+            ("18446744073709551616", None),
+            ("", None),
+            ("k", None),
+            ("-1", None),
+            ("1.5m", None),
+            ("1t", None),
+            ("1 m", None),
+            ("1mb", None),
+        ];
+        for (s, expected) in &cases {
+            assert_eq!(parse_size(s).ok(), *expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_copy_file() {
+        assert_eq!("out/1.txt:output.txt".parse(), Ok(CopyFile::new("out/1.txt".into(), "output.txt".into())));
+        // 只以最后一个 `:` 分隔，外部路径中可以包含 `:`
+        assert_eq!("C:/data:input".parse(), Ok(CopyFile::new("C:/data".into(), "input".into())));
+        for s in &["main", "main:", ":main", ""] {
+            assert_eq!(s.parse::<CopyFile>(), Err(format!("invalid file `{}`, expected host:name", s)));
+        }
+    }
+
+    #[test]
+    fn copy_file_name() {
+        for name in &["main", ".cache", "a.b", "..a"] {
+            assert!(CopyFile::new("/a".into(), name.to_string()).validate().is_ok(), "{}", name);
+        }
+        for name in &["", ".", "..", "a/b", "/main", "main/"] {
+            let message = mount_error(CopyFile::new("/a".into(), name.to_string()).validate());
+            assert_eq!(message, format!("invalid file name `{}` in workdir", name));
+        }
+    }
+
+    #[test]
+    fn bind_target() {
+        for target in &["/mnt", "/", "/usr/./lib"] {
+            assert!(validate_target(target).is_ok(), "{}", target);
+        }
+        for target in &["mnt", "", "/mnt/../etc", "/.."] {
+            assert_eq!(mount_error(validate_target(target)), format!("{} must be an absolute path without `..`", target));
+        }
+    }
+
+    #[test]
+    fn create_mount_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::write(root.join("etc"), "").unwrap();
+        std::os::unix::fs::symlink("usr/lib", root.join("lib")).unwrap();
+        // 以 rootfs 为根解析时指向 rootfs 中的 /usr
+        std::os::unix::fs::symlink("/usr", root.join("usr2")).unwrap();
+        std::os::unix::fs::symlink("/outside", root.join("dangling")).unwrap();
+        let rootfs = root.to_str().unwrap();
+
+        assert!(MountPoint::create(rootfs, "/data/problem", true).unwrap().is_dir().unwrap());
+        assert!(root.join("data/problem").is_dir());
+        assert!(!MountPoint::create(rootfs, "/lib/x/libc.so", false).unwrap().is_dir().unwrap());
+        assert!(root.join("usr/lib/x/libc.so").is_file());
+        MountPoint::create(rootfs, "/usr2/share", true).unwrap();
+        assert!(root.join("usr/share").is_dir());
+        assert!(!dir.path().join("usr").exists());
+
+        assert!(MountPoint::create(rootfs, "/etc/passwd", false).is_err());
+        assert!(MountPoint::create(rootfs, "/dangling/x", true).is_err());
+        assert!(!dir.path().join("outside").exists());
+        assert!(!root.join("outside").exists());
+    }
+
+    #[test]
+    fn validate_mount_points() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::write(root.join("etc"), "").unwrap();
+        std::os::unix::fs::symlink("/outside", root.join("dangling")).unwrap();
+        let rootfs = root.to_str().unwrap();
+
+        for (target, is_dir) in &[("/usr/lib", true), ("/etc", false), ("/data/problem", true), ("/usr/x.so", false)] {
+            assert!(validate_mount_point(rootfs, target, *is_dir).is_ok(), "{}", target);
+        }
+        for (target, is_dir) in &[("/usr/lib", false), ("/etc", true), ("/etc/passwd", false), ("/dangling/x", true)] {
+            let message = mount_error(validate_mount_point(rootfs, target, *is_dir));
+            assert!(message.starts_with(&format!("mount point {} in {}: ", target, rootfs)), "{}", message);
+        }
+        // 检查不会创建任何文件
+        assert!(!root.join("data").exists());
+    }
+}
@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+/// 转发输出时每次读取的最大长度
+const BUFFER_SIZE: usize = 64 * 1024;
+/// 两次检查工作目录占用之间的间隔
+const WATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 创建一对 O_CLOEXEC 的管道，返回 (读取端, 写入端)
+///
+/// 写入端被 dup2 到标准输出后会自动清除 O_CLOEXEC，原描述符则不会泄漏到程序中
+pub fn pipe() -> Result<(i32, i32)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(Error::IOError(std::io::Error::last_os_error()));
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// 结束沙盒内除 1 以外的所有进程
+///
+/// 1 会在程序退出后照常回传运行结果，因此不会被判定为 SystemError
+#[derive(Clone)]
+pub struct Stopper {
+    /// 控制组的 cgroup.procs，其中的 pid 位于外部进程的 pid namespace
+    procs: PathBuf,
+    /// 沙盒内部 1 在外部的 pid
+    init: i32,
+}
+
+impl Stopper {
+    pub fn new(procs: PathBuf, init: i32) -> Self {
+        Stopper { procs, init }
+    }
+
+    pub fn stop(&self) {
+        let procs = std::fs::read_to_string(&self.procs).unwrap_or_default();
+        for pid in procs.lines().filter_map(|pid| pid.trim().parse::<i32>().ok()) {
+            if pid != self.init {
+                unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                }
+            }
+        }
+    }
+}
+
+/// 输出转发的结果
+#[derive(Debug, Default, Clone)]
+pub struct RelayStatus {
+    /// 实际写入目标的字节数，不超过限制
+    pub written: u64,
+    /// 程序的输出是否超出了限制
+    pub exceeded: bool,
+    /// 捕获到内存中的输出，仅在没有目标描述符时使用
+    pub data: Vec<u8>,
+}
+
+/// 将程序经由管道写出的内容转发到目标描述符或捕获到内存中，超出限制时截断并结束程序
+pub struct Relay {
+    handle: JoinHandle<RelayStatus>,
+}
+
+impl Relay {
+    /// from 为管道的读取端，转发结束后会被关闭；to 由调用方管理，为 None 时捕获到内存中
+    pub fn spawn(from: i32, to: Option<i32>, limit: u64, stopper: Stopper) -> Self {
+        let handle = thread::spawn(move || relay(&mut unsafe { File::from_raw_fd(from) }, to, limit, &stopper));
+        Relay { handle }
+    }
+
+    /// 等待程序关闭管道的写入端，即沙盒内的所有进程退出
+    pub fn join(self) -> RelayStatus {
+        self.handle.join().unwrap_or_default()
+    }
+}
+
+fn relay(from: &mut impl Read, to: Option<i32>, limit: u64, stopper: &Stopper) -> RelayStatus {
+    let mut status = RelayStatus::default();
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => return status,
+            Ok(n) => n as u64,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return status,
+        };
+        let allowed = n.min(limit - status.written);
+        match to {
+            Some(to) => {
+                if !write_all(to, &buf[..allowed as usize]) {
+                    warn!("failed to forward output: {}", std::io::Error::last_os_error());
+                }
+            }
+            None => status.data.extend_from_slice(&buf[..allowed as usize]),
+        }
+        status.written += allowed;
+        if allowed < n {
+            status.exceeded = true;
+            // 关闭读取端后程序会收到 SIGPIPE，但忽略了 SIGPIPE 的程序仍可能继续运行，因此直接将其结束
+            stopper.stop();
+            return status;
+        }
+    }
+}
+
+fn write_all(fd: i32, mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        let n = unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return false;
+        }
+        buf = &buf[n as usize..];
+    }
+    true
+}
+
+/// 将内存中的数据经由管道写入程序的标准输入
+///
+/// 写入在单独的线程中进行，程序不读取标准输入时不会阻塞外部进程，也不会与输出的转发互相等待
+pub struct Feeder {
+    handle: JoinHandle<()>,
+}
+
+impl Feeder {
+    /// to 为管道的写入端，写入完成后会被关闭，程序随后读到 EOF
+    pub fn spawn(to: i32, data: Vec<u8>) -> Self {
+        let handle = thread::spawn(move || {
+            // 程序未读完输入就退出时写入会触发 SIGPIPE，作为库使用时外部进程未必忽略了该信号
+            // 在当前线程中屏蔽后 write 只会返回 EPIPE
+            unsafe {
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGPIPE);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            }
+            if !write_all(to, &data) {
+                debug!("stdin is not fully consumed: {}", std::io::Error::last_os_error());
+            }
+            unsafe {
+                libc::close(to);
+            }
+        });
+        Feeder { handle }
+    }
+
+    /// 等待写入完成，或程序退出导致管道被关闭
+    pub fn join(self) {
+        self.handle.join().unwrap_or_default();
+    }
+}
+
+/// 定期检查工作目录的磁盘占用，超出限制时结束程序
+pub struct WorkdirWatcher {
+    finished: Arc<AtomicBool>,
+    handle: JoinHandle<bool>,
+}
+
+impl WorkdirWatcher {
+    pub fn spawn(workdir: String, limit: u64, stopper: Stopper) -> Self {
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = finished.clone();
+        let handle = thread::spawn(move || {
+            while !flag.load(Ordering::SeqCst) {
+                if dir_usage(Path::new(&workdir)) > limit {
+                    stopper.stop();
+                    return true;
+                }
+                thread::sleep(WATCH_INTERVAL);
+            }
+            false
+        });
+        WorkdirWatcher { finished, handle }
+    }
+
+    /// 停止检查，返回运行期间工作目录的占用是否超出了限制
+    pub fn join(self) -> bool {
+        self.finished.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap_or(false)
+    }
+}
+
+/// 目录中所有文件实际占用的磁盘空间，单位 byte，不跟随符号链接
+pub fn dir_usage(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut usage = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        // 使用 st_blocks 而非文件长度，稀疏文件中的空洞不占用磁盘
+        usage += std::os::unix::fs::MetadataExt::blocks(&metadata) * 512;
+        if metadata.is_dir() {
+            usage += dir_usage(&entry.path());
+        }
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+
+    /// 依次返回各个分块的输入，模拟程序分多次写入管道
+    fn chunks(chunks: &[&'static str]) -> impl Read {
+        chunks.iter().fold(Box::new(std::io::empty()) as Box<dyn Read>, |input, chunk| {
+            Box::new(input.chain(Cursor::new(chunk.as_bytes())))
+        })
+    }
+
+    fn stopper() -> Stopper {
+        Stopper::new(PathBuf::from("/nonexistent/cgroup.procs"), 0)
+    }
+
+    #[test]
+    fn truncate() {
+        let cases: [(&[&str], u64, &str, bool); 8] = [
+            (&["hello", " world"], u64::MAX, "hello world", false),
+            (&["hello", " world"], 11, "hello world", false),
+            // 恰好达到限制后再写入 1 byte 即超出
+            (&["hello", " world", "!"], 11, "hello world", true),
+            (&["hello", " world"], 8, "hello wo", true),
+            (&["hello", " world"], 5, "hello", true),
+            (&["hello", " world"], 3, "hel", true),
+            (&["hello"], 0, "", true),
+            (&[], 0, "", false),
+        ];
+        for (input, limit, expected, exceeded) in cases.iter() {
+            let status = relay(&mut chunks(input), None, *limit, &stopper());
+            assert_eq!(status.data, expected.as_bytes(), "{:?} {}", input, limit);
+            assert_eq!(status.written, expected.len() as u64, "{:?} {}", input, limit);
+            assert_eq!(status.exceeded, *exceeded, "{:?} {}", input, limit);
+        }
+    }
+
+    #[test]
+    fn stop_after_limit() {
+        // 超出限制后不再读取剩余的输入
+        let mut input = chunks(&["hello", " world", "!"]);
+        assert!(relay(&mut input, None, 7, &stopper()).exceeded);
+        let mut rest = String::new();
+        input.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "!");
+    }
+
+    #[test]
+    fn forward_to_fd() {
+        let mut file = tempfile::tempfile().unwrap();
+        let status = relay(&mut chunks(&["hello", " world"]), Some(file.as_raw_fd()), 8, &stopper());
+        assert!(status.exceeded);
+        assert_eq!(status.written, 8);
+        assert!(status.data.is_empty());
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello wo");
+    }
+}
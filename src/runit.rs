@@ -1,50 +1,201 @@
 use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
 use std::ptr;
-use std::{thread, time};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::cgroups::CGroup;
+use crate::mount;
+use crate::mount::{Bind, CopyFile};
 use crate::sandbox::Sandbox;
 use crate::seccomp;
-use crate::status::RunnerStatus;
+use crate::status::{RunnerStatus, Rusage};
 use crate::utils;
 
-extern "C" fn timer_thread(sandbox: *mut libc::c_void) -> *mut libc::c_void {
+/// 计时线程是否因超时 kill 了程序
+static TIMEOUT: AtomicBool = AtomicBool::new(false);
+/// 程序是否已经运行结束
+static FINISHED: AtomicBool = AtomicBool::new(false);
+
+/// 沙盒内部 pid = 1 的进程通过管道回传给外部进程的运行结果
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Report {
+    pub status: i32,
+    pub rusage: libc::rusage,
+    /// 程序是否因超出时间限制被计时线程 kill
+    pub timeout: bool,
+    /// 程序运行的真实时间，单位 ms
+    pub real_time_used: i64,
+    /// 沙盒内部 1 与 2 自身消耗的 CPU 时间，需要从控制组的统计中扣除，单位 us
+    pub overhead: i64,
+}
+
+/// 传递给计时线程的参数
+struct Timer {
+    /// 程序开始运行的时间
+    start: Instant,
+    /// 允许运行的真实时间
+    real_limit: Duration,
+    /// 允许使用的 CPU 时间
+    cpu_limit: Option<Duration>,
+    /// 用于统计整个进程树 CPU 时间的控制组
+    cgroup: Option<&'static CGroup>,
+    /// 2 的资源占用
+    rusage: libc::rusage,
+}
+
+/// 计时线程两次检查之间的最长间隔，多线程程序的 CPU 时间增长可能快于真实时间，因此不能只按剩余时间休眠
+const TIMER_INTERVAL: Duration = Duration::from_millis(10);
+
+extern "C" fn timer_thread(timer: *mut libc::c_void) -> *mut libc::c_void {
     trace!("timer thread");
-    let sandbox = unsafe { &mut *(sandbox as *mut Sandbox) };
+    let timer = unsafe { Box::from_raw(timer as *mut Timer) };
 
-    if let Some(time_limit) = sandbox.time_limit {
-        trace!("time limit = {}", time_limit);
-        thread::sleep(time::Duration::from_secs((time_limit / 1000 + 2) as u64));
-        unsafe {
-            killpid(3);
+    trace!("real time limit = {:?}", timer.real_limit);
+    trace!("cpu time limit = {:?}", timer.cpu_limit);
+    // 以程序开始运行的时间为准计算截止时间，不受线程创建耗时的影响
+    let deadline = timer.start + timer.real_limit;
+    // 3 的 CPU 时钟，包含其所有线程的用户态与内核态时间，仅在控制组无法统计 CPU 时间时使用
+    let mut cpu_clock = None;
+    if timer.cpu_limit.is_some() {
+        let mut clock_id = 0;
+        if unsafe { libc::clock_getcpuclockid(3, &mut clock_id) } == 0 {
+            cpu_clock = Some(clock_id);
+        }
+    }
+    loop {
+        if FINISHED.load(Ordering::SeqCst) {
+            return ptr::null_mut();
+        }
+        let mut interval = deadline.saturating_duration_since(Instant::now());
+        if interval.as_nanos() == 0 {
+            break;
+        }
+        if let Some(cpu_limit) = timer.cpu_limit {
+            let cgroup_used = timer
+                .cgroup
+                .and_then(|cgroup| cgroup.cpu_usage())
+                .map(|usage| Duration::from_micros((usage - overhead(&timer.rusage)).max(0) as u64));
+            let cpu_used = match cgroup_used {
+                Some(cpu_used) => Some(cpu_used),
+                None => cpu_clock.and_then(cpu_time),
+            };
+            match cpu_used {
+                Some(cpu_used) if cpu_used > cpu_limit => break,
+                Some(cpu_used) => interval = interval.min(cpu_limit - cpu_used).min(TIMER_INTERVAL),
+                // 3 已经退出，无需继续检查 CPU 时间
+                None => cpu_clock = None,
+            }
         }
-        trace!("timer thread done");
+        thread::sleep(interval);
     }
+    TIMEOUT.store(true, Ordering::SeqCst);
+    // 在 pid namespace 中，pid = 1 的进程向 -1 发送信号会 kill 掉除自身外的所有进程
+    unsafe {
+        libc::kill(-1, libc::SIGKILL);
+    }
+    trace!("timer thread done");
     ptr::null_mut()
 }
 
+/// 沙盒内部 1（包含计时线程）与 2 自身消耗的 CPU 时间，单位 us
+fn overhead(rusage: &libc::rusage) -> i64 {
+    let mut self_rusage = utils::new_rusage();
+    unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, &mut self_rusage);
+    }
+    let init = Rusage::from(&self_rusage);
+    let runit = Rusage::from(rusage);
+    init.utime + init.stime + runit.utime + runit.stime
+}
+
+fn cpu_time(clock_id: libc::clockid_t) -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock_id, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
 pub extern "C" fn runit(sandbox: *mut libc::c_void) -> i32 {
     let sandbox = unsafe { &mut *(sandbox as *mut Sandbox) };
     let exec_args = sandbox.exec_args().unwrap();
 
+    // 先加入控制组，再创建新的 cgroup namespace，使沙盒内部只能看到自身所在的控制组
+    if let Some(cgroup) = &sandbox.control_group {
+        cgroup.join().unwrap();
+    }
+    unsafe {
+        syscall_or_panic!(libc::unshare(libc::CLONE_NEWCGROUP));
+    }
+
+    unsafe {
+        // 等同于 mount --make-rprivate /
+        // 不将挂载传播到其他空间，以免造成挂载混淆
+        syscall_or_panic!(libc::mount(
+            c_str_ptr!(""),
+            c_str_ptr!("/"),
+            c_str_ptr!(""),
+            libc::MS_PRIVATE | libc::MS_REC,
+            ptr::null_mut()
+        ));
+        // tmpfs 工作目录由 1 挂载，2 再将其挂载为 /tmp
+        // 2 在 pivot_root 之前会创建新的挂载命名空间，1 仍能通过此路径在程序结束后复制出文件
+        if let (Some(size), Some(target)) = (sandbox.workdir_size, &sandbox.workdir_mount) {
+            mount::tmpfs_workdir(target, size, sandbox.workdir_inodes, &sandbox.copy_in);
+        }
+        // overlayfs 的上层 tmpfs 同样由 1 挂载，程序结束后 1 再将其中的修改复制出
+        if let Some(layers) = &sandbox.overlay_layers {
+            mount::overlay_layers(&sandbox.rootfs, layers, sandbox.overlay_size);
+        }
+    }
+
     let pid = unsafe { syscall_or_panic!(libc::fork()) };
     // 当前进程（沙盒内部 pid = 1）
     if pid > 0 {
         if pid != 2 {
             panic!("System Error!");
         }
+        // 只保留回传结果的管道，其余继承自外部进程的描述符（如标准输入管道的写入端）都交由 2 使用
+        // 否则 1 会一直持有管道的另一端，程序将永远读不到 EOF
+        utils::close_fds(&[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO, sandbox.report_fd]);
         // 等待进程结束之后，我们才能继续等待 3 这个进程
         // 因为在 3 的父进程没退出的时候，3 这个进程还是归 2 所有的，只有 2 退出后，3 才会作为孤儿进程被 1 接管
-        let _status = wait_it(pid);
+        let (_status, runit_rusage) = wait_rusage(pid);
+        // 2 退出时 3 已经开始运行，以此作为程序的开始时间
+        let start = Instant::now();
 
-        // 创建一个新线程来监听真实时间
+        // 创建一个新线程来监听真实时间与 CPU 时间，超出限制时立即 kill 掉沙盒内的所有进程
+        // 未指定真实时间限制时，沿用 CPU 时间限制向上取整后再加 2s 作为兜底
+        let real_time_limit = match (sandbox.real_time_limit, sandbox.time_limit) {
+            (Some(real_time_limit), _) => Some(Duration::from_millis(real_time_limit as u64)),
+            (None, Some(time_limit)) => Some(Duration::from_secs((time_limit / 1000 + 2) as u64)),
+            (None, None) => None,
+        };
         let mut timer_thread_id = 0;
-        if let Some(_) = sandbox.time_limit {
+        if let Some(real_limit) = real_time_limit {
+            let timer = Box::new(Timer {
+                start,
+                real_limit,
+                cpu_limit: sandbox.time_limit.map(|l| Duration::from_millis(l as u64)),
+                cgroup: sandbox.control_group.as_ref(),
+                rusage: runit_rusage,
+            });
             unsafe {
                 libc::pthread_create(
                     &mut timer_thread_id,
                     ptr::null_mut(),
                     timer_thread,
-                    sandbox as *mut _ as *mut libc::c_void,
+                    Box::into_raw(timer) as *mut libc::c_void,
                 );
             }
         }
@@ -52,52 +203,68 @@ pub extern "C" fn runit(sandbox: *mut libc::c_void) -> i32 {
         // 得益于 Linux 的设计，我们可以使用当前进程（pid = 1）wait 沙盒内部任意孤儿进程
         // 通过三次跳转，我们能够排除掉大部分中间的影响因素，从而获取最接近准确的测量结果（代价是三个额外的进程）
         // 如果因系统异常，3 进程在 2 进程退出前就退出了，那么此处 wait 将会失败，常见原因是资源限制过小，导致无法获取运行必需的资源
-        let status = wait_it(3);
+        let (status, rusage) = wait_rusage(3);
+        let real_time_used = start.elapsed().as_millis() as i64;
 
-        // 在进程结束后取消线程
-        if timer_thread_id != 0 {
-            unsafe {
-                libc::pthread_cancel(timer_thread_id);
+        // 在进程结束后通知计时线程不再 kill
+        // 不能使用 pthread_cancel，强制展开 Rust 的栈帧会导致整个进程 abort，pid = 1 退出时线程会随之结束
+        FINISHED.store(true, Ordering::SeqCst);
+
+        // 程序的子进程可能仍在运行，先将其结束，以免在复制的过程中修改文件
+        // 复制消耗的 CPU 时间计入 overhead，不影响程序的运行结果
+        if !sandbox.copy_out.is_empty() || sandbox.overlay_dir.is_some() {
+            kill_remaining();
+        }
+        if !sandbox.copy_out.is_empty() {
+            send_outputs(sandbox);
+        }
+        if let (Some(layers), Some(dir)) = (&sandbox.overlay_layers, &sandbox.overlay_dir) {
+            if let Err(e) = mount::save_upper(layers, dir) {
+                warn!("failed to save overlay upper dir to {}: {}", dir, e);
             }
         }
 
-        // 此处获取的数值即为我们最终结果的数值
-        debug!("time used   = {}", status.time_used);
-        debug!("memory used = {}", status.memory_used);
-        debug!("exit_code   = {}", status.exit_code);
-        debug!("status      = {}", status.status);
-        debug!("signal      = {}", status.signal);
-        status.result_to_fd(sandbox.result_fd).unwrap();
+        // 此处获取的数值即为我们最终结果的数值，交由外部进程判定结果并输出
+        let report = Report {
+            status,
+            rusage,
+            timeout: TIMEOUT.load(Ordering::SeqCst),
+            real_time_used,
+            overhead: overhead(&runit_rusage),
+        };
+        write_report(sandbox.report_fd, &report);
         return 0;
     }
 
     // 子进程（pid = 2）
+    // tmpfs 中的文件计入控制组的内存，却不属于任何进程，超出内存限制时 OOM killer 可能选中 1，使沙盒无法回传结果
+    // 提高程序被选中的优先级，调高无需特权，1 仍使用默认值
+    if let Err(e) = std::fs::write("/proc/self/oom_score_adj", "1000") {
+        warn!("failed to set oom_score_adj: {}", e);
+    }
     unsafe {
         // 资源限制（使用 setrlimit）
+        // 复制文件已由 1 完成，此时仍是 root，可以将限制设置得高于继承的硬限制，setuid 之后限制保持不变
         let mut rlimit = libc::rlimit {
             rlim_cur: 0,
             rlim_max: 0,
         };
         // CPU 时间限制，单位为 S
+        // 精确的限制由计时线程负责，此处向上取整后再加 1s，仅作为计时线程失效时的兜底
         if let Some(time_limit) = sandbox.time_limit {
-            rlimit.rlim_cur = (time_limit / 1000 + 1) as u64;
-            if time_limit % 1000 > 800 {
-                rlimit.rlim_cur += 1;
-            }
+            rlimit.rlim_cur = ((time_limit + 999) / 1000 + 1) as u64;
             rlimit.rlim_max = rlimit.rlim_cur;
             syscall_or_panic!(libc::setrlimit(libc::RLIMIT_CPU, &rlimit));
         }
         // 内存限制，单位为 kib
+        // 内存本身由控制组限制，此处只限制栈大小
+        // 不使用 RLIMIT_AS，否则会误伤预留大量虚拟地址空间的 JVM、Go 与 Node 程序
         if let Some(memory_limit) = sandbox.memory_limit {
-            rlimit.rlim_cur = memory_limit as u64 * 1024 * 2;
-            rlimit.rlim_max = memory_limit as u64 * 1024 * 2;
-            syscall_or_panic!(libc::setrlimit(libc::RLIMIT_AS, &rlimit));
-
             rlimit.rlim_cur = memory_limit as u64 * 1024 * 2;
             rlimit.rlim_max = memory_limit as u64 * 1024 * 2;
             syscall_or_panic!(libc::setrlimit(libc::RLIMIT_STACK, &rlimit));
         }
-        // 文件大小限制，单位为 bit
+        // 单个文件的大小限制，单位为 byte
         if let Some(file_size_limit) = sandbox.file_size_limit {
             rlimit.rlim_cur = file_size_limit as u64;
             rlimit.rlim_max = file_size_limit as u64;
@@ -107,10 +274,19 @@ pub extern "C" fn runit(sandbox: *mut libc::c_void) -> i32 {
         // 安全机制
         security(&sandbox);
 
+        // 阻止危险的系统调用，其中包括 setrlimit，因此需要在设置资源限制之后
+        apply_seccomp(sandbox);
+
         // 重定向描述符
         syscall_or_panic!(libc::dup2(sandbox.stdin_fd, libc::STDIN_FILENO));
         syscall_or_panic!(libc::dup2(sandbox.stdout_fd, libc::STDOUT_FILENO));
         syscall_or_panic!(libc::dup2(sandbox.stderr_fd, libc::STDERR_FILENO));
+        // 不将外部进程的其他描述符（如另一个沙盒的输出文件）泄漏给程序
+        utils::close_fds(&[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]);
+
+        // Rust 程序启动时会忽略 SIGPIPE，而被忽略的信号会在 execve 后继续保留
+        // 恢复默认行为，使程序在输出管道被关闭后正常结束
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
     }
 
     let ret = unsafe {
@@ -125,72 +301,184 @@ pub extern "C" fn runit(sandbox: *mut libc::c_void) -> i32 {
 }
 
 pub fn wait_it(pid: i32) -> RunnerStatus {
+    let (status, rusage) = wait_rusage(pid);
+    RunnerStatus::new(status, &rusage)
+}
+
+fn wait_rusage(pid: i32) -> (i32, libc::rusage) {
     let mut status: i32 = 0;
     let mut rusage = utils::new_rusage();
     let _ret = unsafe { syscall_or_panic!(libc::wait4(pid, &mut status, 0, &mut rusage)) };
-    let time_used = rusage.ru_utime.tv_sec * 1000
-        + i64::from(rusage.ru_utime.tv_usec) / 1000
-        + rusage.ru_stime.tv_sec * 1000
-        + i64::from(rusage.ru_stime.tv_usec) / 1000;
-    let memory_used = rusage.ru_maxrss;
-    let mut exit_code = 0;
-    let exited = libc::WIFEXITED(status);
-    if exited {
-        exit_code = libc::WEXITSTATUS(status);
+    (status, rusage)
+}
+
+/// kill 沙盒内除当前进程外的所有进程，并等待它们全部退出
+///
+/// 孤儿进程都会成为当前进程的子进程，未被回收的进程在收到 SIGKILL 后仍可能短暂运行
+fn kill_remaining() {
+    unsafe {
+        libc::kill(-1, libc::SIGKILL);
+        loop {
+            if libc::waitpid(-1, ptr::null_mut(), libc::__WALL) < 0
+                && io::Error::last_os_error().raw_os_error() != Some(libc::EINTR)
+            {
+                break;
+            }
+        }
+    }
+}
+
+fn write_report(fd: i32, report: &Report) {
+    let size = mem::size_of::<Report>();
+    unsafe {
+        syscall_or_panic!(libc::write(fd, report as *const _ as *const libc::c_void, size));
     }
-    let signal = if libc::WIFSIGNALED(status) {
-        libc::WTERMSIG(status)
-    } else if libc::WIFSTOPPED(status) {
-        libc::WSTOPSIG(status)
-    } else {
-        0
+}
+
+/// 复制出的文件不存在或不是普通文件
+const OUTPUT_MISSING: i64 = -1;
+/// 复制出的文件超出大小限制
+const OUTPUT_TOO_LARGE: i64 = -2;
+
+/// 在回传运行结果之前，将 tmpfs 工作目录中需要复制出的文件依次写入回传结果的管道
+///
+/// 每个文件以 i64 的长度开头，紧接着为文件内容，无法复制时长度为负数且没有内容，
+/// 文件经由外部进程写入，不会占用沙盒控制组的内存
+fn send_outputs(sandbox: &Sandbox) {
+    // 1 的根目录仍是外部的根目录，需要防止程序借助符号链接读取外部的文件
+    let dirfd = unsafe {
+        syscall_or_panic!(libc::open(
+            c_str_ptr!(sandbox.workdir_mount.clone().unwrap()),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        ))
     };
+    let mut pipe = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(sandbox.report_fd) });
+    let limit = sandbox.copy_out_bytes();
+    for file in &sandbox.copy_out {
+        // O_NONBLOCK 使打开命名管道时不会阻塞
+        let fd = unsafe {
+            libc::openat(
+                dirfd,
+                c_str_ptr!(file.name.clone()),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        let mut source = if fd < 0 { None } else { Some(unsafe { File::from_raw_fd(fd) }) };
+        let metadata = source.as_ref().and_then(|source| source.metadata().ok());
+        // 文件的长度可以通过 truncate 任意放大而不占用空间，因此同时检查长度与实际占用的空间
+        let length = match &metadata {
+            Some(metadata) if !metadata.is_file() => OUTPUT_MISSING,
+            Some(metadata) if metadata.len() > limit || metadata.blocks().saturating_mul(512) > limit => OUTPUT_TOO_LARGE,
+            Some(metadata) => metadata.len() as i64,
+            None => OUTPUT_MISSING,
+        };
+        pipe.write_all(&length.to_ne_bytes()).unwrap();
+        if length > 0 {
+            let copied = io::copy(&mut source.as_mut().unwrap().take(length as u64), &mut *pipe).unwrap();
+            // 写入长度后无法回退，不足的部分以 0 补齐
+            io::copy(&mut io::repeat(0).take(length as u64 - copied), &mut *pipe).unwrap();
+        }
+    }
+    unsafe {
+        libc::close(dirfd);
+    }
+}
+
+/// 接收沙盒回传的文件并写入外部，需要在读取运行结果之前调用
+///
+/// 返回 false 表示沙盒在回传完成前异常退出，或回传的长度超出了 limit
+pub fn receive_outputs(fd: i32, files: &[CopyFile], limit: u64) -> bool {
+    let mut pipe = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    for file in files {
+        let mut length = [0; 8];
+        if pipe.read_exact(&mut length).is_err() {
+            return false;
+        }
+        let length = match i64::from_ne_bytes(length) {
+            OUTPUT_MISSING => {
+                warn!("{} is not a regular file in workdir, skipped", file.name);
+                continue;
+            }
+            OUTPUT_TOO_LARGE => {
+                warn!("{} exceeds the copy out limit, skipped", file.name);
+                continue;
+            }
+            length if length < 0 || length as u64 > limit => return false,
+            length => length as u64,
+        };
+        // 写入失败时仍需读完文件内容，否则之后的数据将会错位
+        let copied = match File::create(&file.host) {
+            Ok(mut target) => io::copy(&mut (&mut *pipe).take(length), &mut target),
+            Err(e) => {
+                warn!("failed to create {}: {}", file.host, e);
+                io::copy(&mut (&mut *pipe).take(length), &mut io::sink())
+            }
+        };
+        match copied {
+            Ok(copied) if copied == length => {}
+            Ok(_) => return false,
+            Err(e) => {
+                warn!("failed to copy {}: {}", file.name, e);
+                return false;
+            }
+        }
+    }
+    true
+}
 
-    RunnerStatus {
-        time_used,
-        memory_used,
-        exit_code,
-        signal,
-        status,
+/// 读取沙盒回传的运行结果，如果沙盒在回传前异常退出，则返回 None
+pub fn read_report(fd: i32) -> Option<Report> {
+    let size = mem::size_of::<Report>();
+    let mut report = mem::MaybeUninit::<Report>::uninit();
+    let ret = unsafe { libc::read(fd, report.as_mut_ptr() as *mut libc::c_void, size) };
+    if ret != size as isize {
+        return None;
     }
+    Some(unsafe { report.assume_init() })
 }
 
 unsafe fn security(sandbox: &Sandbox) {
     // 全局默认权限 755，为运行目录设置特权
     // 因为将会使用 nobody 用户来执行程序，如果没有运行目录 777 权限，将会无法正常工作
-    trace!("chmod {} 777", sandbox.workdir);
-    syscall_or_panic!(libc::chmod(c_str_ptr!(sandbox.workdir.clone()), 0o777,));
-    // 等同于 mount --make-rprivate /
-    // 不将挂载传播到其他空间，以免造成挂载混淆
-    syscall_or_panic!(libc::mount(
-        c_str_ptr!(""),
-        c_str_ptr!("/"),
-        c_str_ptr!(""),
-        libc::MS_PRIVATE | libc::MS_REC,
-        ptr::null_mut()
-    ));
+    // 使用 tmpfs 作为工作目录时挂载的是 tmpfs，外部的工作目录无需修改
+    if sandbox.workdir_size.is_none() {
+        trace!("chmod {} 777", sandbox.workdir);
+        syscall_or_panic!(libc::chmod(c_str_ptr!(sandbox.workdir.clone()), 0o777,));
+    }
+    // pivot_root 会切换同一挂载命名空间中所有进程的根目录，创建新的挂载命名空间，使 1 仍能访问外部的文件
+    syscall_or_panic!(libc::unshare(libc::CLONE_NEWNS));
+
+    // 在 rootfs 自身的挂载点上进行之后的所有挂载，最后将其重新挂载为只读
+    // 否则 rootfs 只受文件权限保护，权限设置有误时程序可以修改其中的文件
+    // overlay 模式下修改只会写入每次运行独立的上层目录，因此保持可写
+    match &sandbox.overlay_layers {
+        Some(layers) => mount::overlay(&sandbox.rootfs, layers),
+        None => mount::bind_root(&sandbox.rootfs),
+    }
 
     // 挂载 /proc 目录，有些语言（比如 rust）依赖此目录
-    syscall_or_panic!(libc::mount(
-        c_str_ptr!("proc"),
-        c_str_ptr!(format!("{}/proc", sandbox.rootfs)),
-        c_str_ptr!("proc"),
-        0,
-        ptr::null_mut(),
-    ));
+    mount::proc(&sandbox.rootfs);
 
     // 挂载运行文件夹，除此目录外程序没有其他目录的写权限
-    syscall_or_panic!(libc::mount(
-        c_str_ptr!(sandbox.workdir.clone()),
-        c_str_ptr!(format!("{}/tmp", sandbox.rootfs)),
-        c_str_ptr!("none"),
-        libc::MS_BIND | libc::MS_PRIVATE,
-        ptr::null_mut(),
-    ));
-
-    // chdir && chroot，隔离文件系统
+    let workdir = sandbox.workdir_mount.as_ref().unwrap_or(&sandbox.workdir);
+    mount::bind(&sandbox.rootfs, &Bind::read_write(workdir.clone(), String::from("/tmp")));
+
+    for bind in &sandbox.binds {
+        mount::bind(&sandbox.rootfs, bind);
+    }
+    for tmpfs in &sandbox.tmpfs {
+        mount::tmpfs(&sandbox.rootfs, tmpfs);
+    }
+    mount::bind_devices(&sandbox.rootfs);
+    if sandbox.overlay_layers.is_none() {
+        mount::remount_root_read_only(&sandbox.rootfs);
+    }
+
+    // 以 rootfs 作为新的根目录，原来的根目录被叠放在其上，随后将其分离
+    // 与 chroot 不同，外部的挂载从此不再可达，/proc/self/mountinfo 中也只剩下 rootfs 与沙盒自身的挂载
     syscall_or_panic!(libc::chdir(c_str_ptr!(sandbox.rootfs.clone())));
-    syscall_or_panic!(libc::chroot(c_str_ptr!(".")));
+    syscall_or_panic!(libc::syscall(libc::SYS_pivot_root, c_str_ptr!("."), c_str_ptr!(".")));
+    syscall_or_panic!(libc::umount2(c_str_ptr!("."), libc::MNT_DETACH));
     syscall_or_panic!(libc::chdir(c_str_ptr!("/tmp")));
 
     // 设置主机名
@@ -200,68 +488,77 @@ unsafe fn security(sandbox: &Sandbox) {
     // 修改用户为 nobody
     syscall_or_panic!(libc::setgid(65534));
     syscall_or_panic!(libc::setuid(65534));
+}
 
+unsafe fn apply_seccomp(sandbox: &Sandbox) {
     let filter = seccomp::SeccompFilter::new(
-        deny_syscalls().into_iter().collect(),
+        deny_syscalls(&sandbox.allowed_syscalls).into_iter().collect(),
         seccomp::SeccompAction::Allow,
     )
     .unwrap();
     seccomp::SeccompFilter::apply(filter.try_into().unwrap()).unwrap();
 }
 
-/// 阻止危险的系统调用
+/// 阻止的危险系统调用
 ///
 /// 参照 Docker 文档 [significant-syscalls-blocked-by-the-default-profile](https://docs.docker.com/engine/security/seccomp/#significant-syscalls-blocked-by-the-default-profile) 一节
-fn deny_syscalls() -> Vec<seccomp::SyscallRuleSet> {
-    vec![
-        deny_syscall(libc::SYS_acct),
-        deny_syscall(libc::SYS_add_key),
-        deny_syscall(libc::SYS_bpf),
-        deny_syscall(libc::SYS_clock_adjtime),
-        deny_syscall(libc::SYS_clock_settime),
-        deny_syscall(libc::SYS_create_module),
-        deny_syscall(libc::SYS_delete_module),
-        deny_syscall(libc::SYS_finit_module),
-        deny_syscall(libc::SYS_get_kernel_syms),
-        deny_syscall(libc::SYS_get_mempolicy),
-        deny_syscall(libc::SYS_init_module),
-        deny_syscall(libc::SYS_ioperm),
-        deny_syscall(libc::SYS_iopl),
-        deny_syscall(libc::SYS_kcmp),
-        deny_syscall(libc::SYS_kexec_file_load),
-        deny_syscall(libc::SYS_kexec_load),
-        deny_syscall(libc::SYS_keyctl),
-        deny_syscall(libc::SYS_lookup_dcookie),
-        deny_syscall(libc::SYS_mbind),
-        deny_syscall(libc::SYS_mount),
-        deny_syscall(libc::SYS_move_pages),
-        deny_syscall(libc::SYS_name_to_handle_at),
-        deny_syscall(libc::SYS_nfsservctl),
-        deny_syscall(libc::SYS_open_by_handle_at),
-        deny_syscall(libc::SYS_perf_event_open),
-        deny_syscall(libc::SYS_personality),
-        deny_syscall(libc::SYS_pivot_root),
-        deny_syscall(libc::SYS_process_vm_readv),
-        deny_syscall(libc::SYS_process_vm_writev),
-        deny_syscall(libc::SYS_ptrace),
-        deny_syscall(libc::SYS_query_module),
-        deny_syscall(libc::SYS_quotactl),
-        deny_syscall(libc::SYS_reboot),
-        deny_syscall(libc::SYS_request_key),
-        deny_syscall(libc::SYS_set_mempolicy),
-        deny_syscall(libc::SYS_setns),
-        deny_syscall(libc::SYS_setrlimit),
-        deny_syscall(libc::SYS_settimeofday),
-        deny_syscall(libc::SYS_swapon),
-        deny_syscall(libc::SYS_swapoff),
-        deny_syscall(libc::SYS_sysfs),
-        deny_syscall(libc::SYS__sysctl),
-        deny_syscall(libc::SYS_umount2),
-        deny_syscall(libc::SYS_unshare),
-        deny_syscall(libc::SYS_uselib),
-        deny_syscall(libc::SYS_userfaultfd),
-        deny_syscall(libc::SYS_ustat),
-    ]
+pub(crate) const DENIED_SYSCALLS: [(&str, i64); 47] = [
+    ("acct", libc::SYS_acct),
+    ("add_key", libc::SYS_add_key),
+    ("bpf", libc::SYS_bpf),
+    ("clock_adjtime", libc::SYS_clock_adjtime),
+    ("clock_settime", libc::SYS_clock_settime),
+    ("create_module", libc::SYS_create_module),
+    ("delete_module", libc::SYS_delete_module),
+    ("finit_module", libc::SYS_finit_module),
+    ("get_kernel_syms", libc::SYS_get_kernel_syms),
+    ("get_mempolicy", libc::SYS_get_mempolicy),
+    ("init_module", libc::SYS_init_module),
+    ("ioperm", libc::SYS_ioperm),
+    ("iopl", libc::SYS_iopl),
+    ("kcmp", libc::SYS_kcmp),
+    ("kexec_file_load", libc::SYS_kexec_file_load),
+    ("kexec_load", libc::SYS_kexec_load),
+    ("keyctl", libc::SYS_keyctl),
+    ("lookup_dcookie", libc::SYS_lookup_dcookie),
+    ("mbind", libc::SYS_mbind),
+    ("mount", libc::SYS_mount),
+    ("move_pages", libc::SYS_move_pages),
+    ("name_to_handle_at", libc::SYS_name_to_handle_at),
+    ("nfsservctl", libc::SYS_nfsservctl),
+    ("open_by_handle_at", libc::SYS_open_by_handle_at),
+    ("perf_event_open", libc::SYS_perf_event_open),
+    ("personality", libc::SYS_personality),
+    ("pivot_root", libc::SYS_pivot_root),
+    ("process_vm_readv", libc::SYS_process_vm_readv),
+    ("process_vm_writev", libc::SYS_process_vm_writev),
+    ("ptrace", libc::SYS_ptrace),
+    ("query_module", libc::SYS_query_module),
+    ("quotactl", libc::SYS_quotactl),
+    ("reboot", libc::SYS_reboot),
+    ("request_key", libc::SYS_request_key),
+    ("set_mempolicy", libc::SYS_set_mempolicy),
+    ("setns", libc::SYS_setns),
+    ("setrlimit", libc::SYS_setrlimit),
+    ("settimeofday", libc::SYS_settimeofday),
+    ("swapon", libc::SYS_swapon),
+    ("swapoff", libc::SYS_swapoff),
+    ("sysfs", libc::SYS_sysfs),
+    ("_sysctl", libc::SYS__sysctl),
+    ("umount2", libc::SYS_umount2),
+    ("unshare", libc::SYS_unshare),
+    ("uselib", libc::SYS_uselib),
+    ("userfaultfd", libc::SYS_userfaultfd),
+    ("ustat", libc::SYS_ustat),
+];
+
+/// 阻止危险的系统调用，allowed 中的系统调用除外
+fn deny_syscalls(allowed: &[String]) -> Vec<seccomp::SyscallRuleSet> {
+    DENIED_SYSCALLS
+        .iter()
+        .filter(|(name, _)| !allowed.iter().any(|allowed| allowed == name))
+        .map(|(_, number)| deny_syscall(*number))
+        .collect()
 }
 
 #[inline(always)]
@@ -274,17 +571,3 @@ fn deny_syscall(syscall_number: i64) -> seccomp::SyscallRuleSet {
         )],
     )
 }
-
-unsafe fn killpid(pid: i32) {
-    let mut status = 0;
-
-    trace!("kill pid {} if exists", pid);
-    // > 0: 对应子进程退出但未回收资源
-    // = 0: 对应子进程存在但未退出
-    // 如果在运行过程中上层异常中断，则需要 kill 子进程并回收资源
-    if libc::waitpid(pid, &mut status, libc::WNOHANG) >= 0 {
-        trace!("kill pid {}", pid);
-        libc::kill(pid, 9);
-        libc::waitpid(pid, &mut status, 0);
-    }
-}
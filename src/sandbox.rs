@@ -1,60 +1,183 @@
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::IntoRawFd;
 use std::ptr;
+use std::time::Instant;
 
 use libc;
 
-use crate::cgroups::{CGroup, CGroupOptions};
-use crate::error::Result;
+use crate::checker;
+use crate::checker::CompareMode;
+use crate::cgroups::{CGroup, CGroupEvents, CGroupOptions, IoLimit, CPU_PERIOD};
+use crate::error::{Error, Result};
 use crate::exec_args::ExecArgs;
+use crate::mount;
+use crate::mount::{Bind, CopyFile, Tmpfs};
+use crate::output;
+use crate::output::{Feeder, Relay, Stopper, WorkdirWatcher};
 use crate::runit;
-use crate::runit::wait_it;
-use crate::status::RunnerStatus;
+use crate::runit::{wait_it, Report};
+use crate::status::{Limits, Metadata, ResultFormat, RunnerStatus, Verdict};
+use crate::utils;
 
 const STACK_SIZE: usize = 1024 * 1024;
 
 pub struct Sandbox {
-    inner_args: Vec<String>,
+    pub(crate) inner_args: Vec<String>,
     pub workdir: String,
     pub rootfs: String,
     result: Option<String>,
     pub result_fd: i32,
+    pub result_format: ResultFormat,
+    /// 沙盒内部回传运行结果所用管道的写入端
+    pub report_fd: i32,
     stdin: Option<String>,
     pub stdin_fd: i32,
+    /// 经由管道写入标准输入的数据，优先于 stdin_fd
+    pub stdin_data: Option<Vec<u8>>,
     stdout: Option<String>,
     pub stdout_fd: i32,
     stderr: Option<String>,
     pub stderr_fd: i32,
     pub time_limit: Option<i32>,
+    pub real_time_limit: Option<i32>,
     pub memory_limit: Option<i32>,
     pub file_size_limit: Option<i32>,
+    /// 标准输出与标准错误的大小限制，单位 byte
+    pub stdout_limit: Option<u64>,
+    pub stderr_limit: Option<u64>,
+    /// 是否将标准输出与标准错误捕获到内存中，捕获的内容位于运行结果的 stdout 与 stderr
+    pub capture_stdout: bool,
+    pub capture_stderr: bool,
+    /// 工作目录的总磁盘占用限制，单位 byte，使用 tmpfs 工作目录时由 workdir_size 代替
+    pub workdir_limit: Option<u64>,
+    /// 标准答案文件，指定后会在程序正常结束时将其输出与之比较
+    answer: Option<String>,
+    pub compare_mode: CompareMode,
+    /// 额外的绑定挂载，按顺序挂载在 /proc 与 /tmp 之后
+    pub binds: Vec<Bind>,
+    /// 额外的 tmpfs，挂载在绑定挂载之后
+    pub tmpfs: Vec<Tmpfs>,
+    /// 以 rootfs 为只读的下层挂载 overlayfs，程序对 rootfs 的修改写入每次运行独立的上层目录
+    pub overlay: bool,
+    /// 指定时运行结束后将上层目录中的修改复制到其中的 upper 目录以便检查，否则丢弃
+    pub overlay_dir: Option<String>,
+    /// 上层目录位于此大小的 tmpfs 中，单位 byte，写满后程序对 rootfs 的修改会失败
+    pub overlay_size: u64,
+    /// 本次运行中上层 tmpfs 在外部的挂载点，仅在 run 期间存在
+    pub(crate) overlay_layers: Option<String>,
+    /// 以此大小的 tmpfs 作为沙盒内部的 /tmp，不再挂载外部的工作目录，单位 byte
+    pub workdir_size: Option<u64>,
+    /// 本次运行中 tmpfs 工作目录在外部的挂载点，仅在 run 期间存在
+    pub(crate) workdir_mount: Option<String>,
+    /// tmpfs 工作目录的 inode 数量限制，0 为不限制
+    pub workdir_inodes: u64,
+    /// 运行前复制到 tmpfs 工作目录中的文件
+    pub copy_in: Vec<CopyFile>,
+    /// 运行后从 tmpfs 工作目录中复制出的文件，只复制普通文件
+    pub copy_out: Vec<CopyFile>,
+    /// 复制出的单个文件的大小限制，单位 byte，超出时不复制，未指定或大于 workdir_size 时以 workdir_size 为准
+    pub copy_out_limit: Option<u64>,
+    /// 额外的环境变量
+    pub envs: Vec<(String, String)>,
+    /// 默认会被阻止、但允许此程序使用的系统调用名称，如 `personality`
+    pub allowed_syscalls: Vec<String>,
+    /// 特殊评测程序所在的沙盒，指定后代替 compare_mode 判定输出是否正确
+    checker: Option<Box<Sandbox>>,
     pub cgroup: i32,
+    /// 沙盒控制组的上级控制组，相对于层级的根目录，默认为当前进程所在的控制组
+    pub cgroup_parent: Option<String>,
     pub pids: i32,
+    /// CPU 配额，单位为核数
+    pub cpu_quota: Option<f64>,
+    pub cpuset_cpus: Option<String>,
+    pub cpuset_mems: Option<String>,
+    /// 工作目录所在块设备的读写速率限制，单位 byte/s
+    pub io_read_bps: Option<u64>,
+    pub io_write_bps: Option<u64>,
+    /// 工作目录所在块设备的读写次数限制，单位 次/s
+    pub io_read_iops: Option<u64>,
+    pub io_write_iops: Option<u64>,
+    /// 交由沙盒内部持有的描述符（如交互模式中连接两个沙盒的管道），外部进程在创建沙盒后将其关闭，
+    /// 使管道的另一端能够在程序退出时读到 EOF；仍被用于转发输出的描述符在转发结束后关闭
+    pub(crate) handover_fds: Vec<i32>,
+    /// 本次运行所用的控制组，仅在 run 期间存在，沙盒内部通过它将自身加入控制组
+    pub control_group: Option<CGroup>,
 }
 
 impl Sandbox {
-    pub fn new(args: Vec<String>) -> Self {
+    /// 创建运行 args 的沙盒，参数中包含 NUL 字节时返回错误
+    ///
+    /// 未通过 builder 修改的设置均为默认值：
+    /// - 工作目录为当前目录 `.`，rootfs 为空，必须在运行前指定
+    /// - cgroup 为 0，自动检测 cgroup 版本，控制组创建在沙盒自身所在的控制组下
+    /// - result_fd 为 -1，不输出运行结果，通过 result 指定
+    /// - 标准输入、标准输出与标准错误继承自外部进程
+    /// - 不限制任何资源，答案按行比较
+    pub fn new(args: Vec<String>) -> Result<Self> {
         // 为什么传 String 而非 &str，因为数据最终会被 unsafe 到子进程中并且主动 forget 与 drop，如果使用 str 会导致生命周期完全混乱
         // 因此使用 String，在传递时复制而非传递地址
+        for arg in &args {
+            try_cstr!(arg.as_str());
+        }
         let mut v = vec![String::from("/usr/bin/runit")];
         // let mut v = vec![];
         v.extend(args);
-        Sandbox {
+        Ok(Sandbox {
             inner_args: v,
-            workdir: String::from(""),
+            workdir: String::from("."),
             rootfs: String::from(""),
             result: None,
-            result_fd: 1,
+            result_fd: -1,
+            result_format: ResultFormat::Text,
+            report_fd: -1,
             stdin: None,
             stdin_fd: 0,
+            stdin_data: None,
             stdout: None,
             stdout_fd: 1,
             stderr: None,
             stderr_fd: 2,
             time_limit: None,
+            real_time_limit: None,
             memory_limit: None,
             file_size_limit: None,
-            cgroup: 1,
+            stdout_limit: None,
+            stderr_limit: None,
+            capture_stdout: false,
+            capture_stderr: false,
+            workdir_limit: None,
+            answer: None,
+            compare_mode: CompareMode::Lines,
+            binds: vec![],
+            tmpfs: vec![],
+            overlay: false,
+            overlay_dir: None,
+            overlay_size: mount::DEFAULT_OVERLAY_SIZE,
+            overlay_layers: None,
+            workdir_size: None,
+            workdir_mount: None,
+            workdir_inodes: 0,
+            copy_in: vec![],
+            copy_out: vec![],
+            copy_out_limit: None,
+            envs: vec![],
+            allowed_syscalls: vec![],
+            checker: None,
+            cgroup: 0,
+            cgroup_parent: None,
             pids: 0,
-        }
+            cpu_quota: None,
+            cpuset_cpus: None,
+            cpuset_mems: None,
+            io_read_bps: None,
+            io_write_bps: None,
+            io_read_iops: None,
+            io_write_iops: None,
+            handover_fds: vec![],
+            control_group: None,
+        })
     }
     // 工作目录，如果没提供则会使用当前目录，始终会被 mount 为沙盒内部的 /tmp
     pub fn workdir(mut self, s: String) -> Self {
@@ -66,14 +189,17 @@ impl Sandbox {
         self.rootfs = s;
         self
     }
-    pub fn result(mut self, s: String) -> Self {
+    // 作为库使用时默认不输出运行结果，`/STDOUT/` 表示输出到标准输出，文件无法打开时返回错误
+    pub fn result(mut self, s: String) -> Result<Self> {
         if s != "/STDOUT/" {
             debug!("result file = {}", s);
             self.result = Some(s.clone());
-            self.result_fd = unsafe {
-                syscall_or_panic!(libc::open(c_str_ptr!(s), libc::O_CREAT | libc::O_RDWR, 0o644))
-            };
         }
+        self.result_fd = open_result(&s)?;
+        Ok(self)
+    }
+    pub fn result_format(mut self, format: ResultFormat) -> Self {
+        self.result_format = format;
         self
     }
     pub fn stdin(mut self, s: String) -> Self {
@@ -83,15 +209,35 @@ impl Sandbox {
             self.stdin_fd = unsafe {
                 syscall_or_panic!(libc::open(c_str_ptr!(s), libc::O_RDONLY, 0o644))
             };
+            self.stdin_data = None;
         }
         self
     }
+    // 从内存中的数据读取标准输入，无需先写入文件，由外部进程在运行期间经由管道写入
+    pub fn stdin_bytes<T: Into<Vec<u8>>>(mut self, data: T) -> Self {
+        self.stdin_data = Some(data.into());
+        self
+    }
+    pub fn stdin_string(self, s: String) -> Self {
+        self.stdin_bytes(s)
+    }
+    // 从任意可读的描述符读取标准输入，如另一个程序的管道，描述符由调用方管理
+    pub fn stdin_from_fd(mut self, fd: i32) -> Self {
+        if self.stdin.take().is_some() {
+            unsafe {
+                libc::close(self.stdin_fd);
+            }
+        }
+        self.stdin_fd = fd;
+        self.stdin_data = None;
+        self
+    }
     pub fn stdout(mut self, s: String) -> Self {
         if s != "/STDOUT/" {
             debug!("stdout file = {}", s);
             self.stdout = Some(s.clone());
             self.stdout_fd = unsafe {
-                syscall_or_panic!(libc::open(c_str_ptr!(s), libc::O_CREAT | libc::O_RDWR, 0o644))
+                syscall_or_panic!(libc::open(c_str_ptr!(s), libc::O_CREAT | libc::O_RDWR | libc::O_TRUNC, 0o644))
             };
         }
         self
@@ -101,7 +247,7 @@ impl Sandbox {
             debug!("stderr file = {}", s);
             self.stderr = Some(s.clone());
             self.stderr_fd = unsafe {
-                syscall_or_panic!(libc::open(c_str_ptr!(s), libc::O_CREAT | libc::O_RDWR, 0o644))
+                syscall_or_panic!(libc::open(c_str_ptr!(s), libc::O_CREAT | libc::O_RDWR | libc::O_TRUNC, 0o644))
             };
         }
         self
@@ -112,6 +258,12 @@ impl Sandbox {
         }
         self
     }
+    pub fn real_time_limit(mut self, l: i32) -> Self {
+        if l != 0 {
+            self.real_time_limit = Some(l);
+        }
+        self
+    }
     pub fn memory_limit(mut self, l: i32) -> Self {
         if l != 0 {
             self.memory_limit = Some(l);
@@ -124,31 +276,465 @@ impl Sandbox {
         }
         self
     }
+    // 与 file_size_limit 不同，超出限制时程序会被结束，已经输出的内容会在限制处截断后保留
+    pub fn stdout_limit(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.stdout_limit = Some(l);
+        }
+        self
+    }
+    pub fn stderr_limit(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.stderr_limit = Some(l);
+        }
+        self
+    }
+    // 通过管道将标准输出捕获到内存中，无需再指定输出文件，limit 同 stdout_limit，为 0 时不限制
+    pub fn capture_stdout(mut self, limit: u64) -> Self {
+        self.capture_stdout = true;
+        self.stdout_limit(limit)
+    }
+    pub fn capture_stderr(mut self, limit: u64) -> Self {
+        self.capture_stderr = true;
+        self.stderr_limit(limit)
+    }
+    pub fn workdir_limit(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.workdir_limit = Some(l);
+        }
+        self
+    }
+    // 输出来自 stdout 指定的文件或捕获的内容，两者都未指定时会自动捕获
+    pub fn answer(mut self, s: String) -> Self {
+        if !s.is_empty() {
+            self.answer = Some(s);
+        }
+        self
+    }
+    pub fn compare_mode(mut self, mode: CompareMode) -> Self {
+        self.compare_mode = mode;
+        self
+    }
+    // 如 `Bind::read_only("/data/1".into(), "/data".into())`，沙盒内部的挂载点不存在时会被创建
+    pub fn bind(mut self, bind: Bind) -> Self {
+        self.binds.push(bind);
+        self
+    }
+    // rootfs 为只读，需要在工作目录以外写入文件时（如 `/dev/shm`）使用，挂载点不存在时会被创建
+    pub fn tmpfs(mut self, tmpfs: Tmpfs) -> Self {
+        self.tmpfs.push(tmpfs);
+        self
+    }
+    // 需要写入 rootfs（如语言的缓存目录）而又不能修改共享的 rootfs 时使用
+    pub fn overlay(mut self, overlay: bool) -> Self {
+        self.overlay = overlay;
+        self
+    }
+    // 其中的 upper 会在每次运行前清空，因此只保留最后一次运行的修改，目录不能位于 rootfs 之中
+    pub fn overlay_dir(mut self, s: String) -> Self {
+        if !s.is_empty() {
+            self.overlay = true;
+            self.overlay_dir = Some(s);
+        }
+        self
+    }
+    // 上层目录占用的是内存，计入沙盒的内存使用，默认为 DEFAULT_OVERLAY_SIZE
+    pub fn overlay_size(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.overlay_size = l;
+        }
+        self
+    }
+    // 程序写入的内容不会落到外部的磁盘上，运行结束后只保留 copy_out 中的文件
+    pub fn workdir_size(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.workdir_size = Some(l);
+        }
+        self
+    }
+    pub fn workdir_inodes(mut self, l: u64) -> Self {
+        self.workdir_inodes = l;
+        self
+    }
+    pub fn copy_in(mut self, file: CopyFile) -> Self {
+        self.copy_in.push(file);
+        self
+    }
+    pub fn copy_out(mut self, file: CopyFile) -> Self {
+        self.copy_out.push(file);
+        self
+    }
+    pub fn copy_out_limit(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.copy_out_limit = Some(l);
+        }
+        self
+    }
+    pub fn env(mut self, key: String, value: String) -> Self {
+        self.envs.push((key, value));
+        self
+    }
+    // 只能放开默认阻止的系统调用，名称不在阻止列表中时没有效果
+    pub fn allow_syscall(mut self, name: String) -> Self {
+        self.allowed_syscalls.push(name);
+        self
+    }
+    // 评测程序的资源限制与 rootfs 由传入的沙盒决定，工作目录与标准输入输出会在运行时被替换
+    pub fn checker(mut self, checker: Sandbox) -> Self {
+        self.checker = Some(Box::new(checker));
+        self
+    }
     pub fn cgroup(mut self, l: i32) -> Self {
         if l == 1 || l == 2 {
             self.cgroup = l;
         }
         self
     }
+    // 如 `system.slice/judge.service`，在 systemd 中运行时应指向 Delegate=yes 的 service 或 scope
+    pub fn cgroup_parent(mut self, s: String) -> Self {
+        if !s.is_empty() {
+            self.cgroup_parent = Some(s);
+        }
+        self
+    }
     pub fn pids(mut self, l: i32) -> Self {
         if l > 0 {
             self.pids = l;
         }
         self
     }
+    // 如 1.5 表示每个周期内最多使用 1.5 个核的 CPU 时间
+    pub fn cpu_quota(mut self, l: f64) -> Self {
+        if l > 0.0 {
+            self.cpu_quota = Some(l);
+        }
+        self
+    }
+    // 允许使用的 CPU 列表，如 `0-3,5`，用于将每个沙盒固定在不同的核上
+    pub fn cpuset_cpus(mut self, s: String) -> Self {
+        if !s.is_empty() {
+            self.cpuset_cpus = Some(s);
+        }
+        self
+    }
+    pub fn cpuset_mems(mut self, s: String) -> Self {
+        if !s.is_empty() {
+            self.cpuset_mems = Some(s);
+        }
+        self
+    }
+    pub fn io_read_bps(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.io_read_bps = Some(l);
+        }
+        self
+    }
+    pub fn io_write_bps(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.io_write_bps = Some(l);
+        }
+        self
+    }
+    pub fn io_read_iops(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.io_read_iops = Some(l);
+        }
+        self
+    }
+    pub fn io_write_iops(mut self, l: u64) -> Self {
+        if l > 0 {
+            self.io_write_iops = Some(l);
+        }
+        self
+    }
+    /// 实际使用的复制出的文件大小限制，不会超过 tmpfs 工作目录的大小
+    pub(crate) fn copy_out_bytes(&self) -> u64 {
+        let size = self.workdir_size.unwrap_or(0);
+        self.copy_out_limit.map_or(size, |limit| limit.min(size))
+    }
     pub fn exec_args(&self) -> Result<ExecArgs> {
-        ExecArgs::build(&self.inner_args)
+        ExecArgs::build(&self.inner_args, &self.envs)
+    }
+    pub fn limits(&self) -> Limits {
+        Limits {
+            time_limit: self.time_limit,
+            real_time_limit: self.real_time_limit,
+            memory_limit: self.memory_limit,
+            file_size_limit: self.file_size_limit,
+            stdout_limit: self.stdout_limit,
+            stderr_limit: self.stderr_limit,
+            workdir_limit: self.workdir_limit,
+            pids: if self.pids > 0 { Some(self.pids) } else { None },
+            cpu_quota: self.cpu_quota,
+            cpuset_cpus: self.cpuset_cpus.clone(),
+            cpuset_mems: self.cpuset_mems.clone(),
+            io_read_bps: self.io_read_bps,
+            io_write_bps: self.io_write_bps,
+            io_read_iops: self.io_read_iops,
+            io_write_iops: self.io_write_iops,
+        }
+    }
+    /// 工作目录所在块设备的 I/O 限制，未设置任何限制时为 None
+    fn io_limit(&self) -> Option<IoLimit> {
+        if self.io_read_bps.is_none()
+            && self.io_write_bps.is_none()
+            && self.io_read_iops.is_none()
+            && self.io_write_iops.is_none()
+        {
+            return None;
+        }
+        let workdir = if self.workdir.is_empty() { "." } else { &self.workdir };
+        let device = match utils::block_device(workdir) {
+            Some(device) => device,
+            None => {
+                warn!("workdir {} is not backed by a block device, I/O limits are ignored", workdir);
+                return None;
+            }
+        };
+        Some(IoLimit {
+            device,
+            read_bps: self.io_read_bps.unwrap_or(0),
+            write_bps: self.io_write_bps.unwrap_or(0),
+            read_iops: self.io_read_iops.unwrap_or(0),
+            write_iops: self.io_write_iops.unwrap_or(0),
+        })
+    }
+    fn metadata(&self, wall_time: u64) -> Metadata {
+        Metadata {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            // 去掉开头的 /usr/bin/runit
+            command: self.inner_args[1..].to_vec(),
+            rootfs: self.rootfs.clone(),
+            workdir: self.workdir.clone(),
+            cgroup: self.cgroup,
+            wall_time,
+        }
+    }
+}
+
+impl Drop for Sandbox {
+    /// 关闭由沙盒自身打开的文件，调用方传入的描述符仍由调用方管理
+    fn drop(&mut self) {
+        let files = [
+            (&self.result, self.result_fd),
+            (&self.stdin, self.stdin_fd),
+            (&self.stdout, self.stdout_fd),
+            (&self.stderr, self.stderr_fd),
+        ];
+        for (_, fd) in files.iter().filter(|(path, _)| path.is_some()) {
+            unsafe {
+                libc::close(*fd);
+            }
+        }
     }
 }
 
 impl Sandbox {
-    pub fn run(&mut self) -> RunnerStatus {
+    pub fn run(&mut self) -> Result<RunnerStatus> {
+        let start = Instant::now();
+        let status = self.judge()?;
+        self.write_result(&status, start)?;
+        Ok(status)
+    }
+
+    /// 运行程序并判定结果，包括与答案的比较，但不输出运行结果
+    pub fn judge(&mut self) -> Result<RunnerStatus> {
+        // 需要与答案比较但输出没有写入文件时，将其捕获到内存中，比较完成后丢弃
+        let capture = (self.answer.is_some() || self.checker.is_some()) && self.stdout.is_none() && !self.capture_stdout;
+        self.capture_stdout |= capture;
+        let status = self.execute();
+        self.capture_stdout &= !capture;
+        let mut status = status?;
+        self.check(&mut status)?;
+        if capture {
+            status.stdout = None;
+        }
+        Ok(status)
+    }
+
+    /// 程序正常结束时判定其输出是否正确，以此作为最终结果
+    fn check(&mut self, status: &mut RunnerStatus) -> Result<()> {
+        if status.verdict != Verdict::Accepted {
+            return Ok(());
+        }
+        let output = match self.output(status)? {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let answer = match &self.answer {
+            Some(answer) => try_io!(std::fs::read(answer)),
+            None => vec![],
+        };
+        let result = match self.checker.as_mut() {
+            Some(checker) => {
+                let input = match (&self.stdin_data, &self.stdin) {
+                    (Some(data), _) => Cow::Borrowed(data.as_slice()),
+                    (None, Some(path)) => Cow::Owned(try_io!(std::fs::read(path))),
+                    (None, None) => Cow::Borrowed(&[][..]),
+                };
+                checker::special_judge(checker, &input, &output, &answer)?
+            }
+            None if self.answer.is_some() => checker::check(&output, &answer, self.compare_mode),
+            None => return Ok(()),
+        };
+        status.verdict = result.verdict;
+        status.check = Some(result);
+        Ok(())
+    }
+
+    /// 捕获的输出或写入的输出文件，两者都没有时为 None
+    fn output<'a>(&self, status: &'a RunnerStatus) -> Result<Option<Cow<'a, [u8]>>> {
+        Ok(match (&status.stdout, &self.stdout) {
+            (Some(output), _) => Some(Cow::Borrowed(output.as_slice())),
+            (None, Some(path)) => Some(Cow::Owned(try_io!(std::fs::read(path)))),
+            (None, None) => None,
+        })
+    }
+
+    /// 将运行结果输出到 result_fd，start 为沙盒开始创建的时间，未指定 result 时不输出
+    pub fn write_result(&self, status: &RunnerStatus, start: Instant) -> Result<()> {
+        if self.result_fd < 0 {
+            return Ok(());
+        }
+        let metadata = self.metadata(start.elapsed().as_millis() as u64);
+        status.result_to_fd(self.result_fd, self.result_format, &self.limits(), &metadata)
+    }
+
+    /// 运行程序并判定结果，但不输出运行结果
+    pub(crate) fn execute(&mut self) -> Result<RunnerStatus> {
+        let running = self.spawn();
+        self.close_handover_fds();
+        self.wait(running?)
+    }
+
+    /// 关闭交出的描述符，沙盒内部已持有其副本
+    ///
+    /// 创建沙盒失败时同样需要关闭，否则管道另一端的程序将一直等待
+    pub(crate) fn close_handover_fds(&mut self) {
+        for fd in self.handover_fds.drain(..) {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+
+    /// 检查挂载点，/tmp 之下的挂载点位于工作目录中
+    fn validate_mount_point(&self, target: &str, dir: bool) -> Result<()> {
+        let inner = match std::path::Path::new(target).strip_prefix("/tmp") {
+            Ok(inner) => inner,
+            Err(_) => return mount::validate_mount_point(&self.rootfs, target, dir),
+        };
+        if self.workdir_size.is_none() {
+            return mount::validate_mount_point(&self.workdir, &format!("/{}", inner.display()), dir);
+        }
+        // tmpfs 工作目录在沙盒内部才会挂载，其中只有复制进来的文件
+        let name = inner.iter().next();
+        match self.copy_in.iter().find(|file| name == Some(std::ffi::OsStr::new(&file.name))) {
+            Some(_) if inner.iter().count() == 1 && !dir => Ok(()),
+            Some(file) => Err(Error::MountError(format!(
+                "mount point {} conflicts with the copied file {}",
+                target, file.name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// 创建沙盒，但不启动任何转发线程
+    ///
+    /// 沙盒内部由不带 CLONE_VM 的 clone 创建，只复制调用线程，此时若有其他线程持有 malloc 等锁，
+    /// 沙盒内部的内存分配可能永远阻塞，因此调用时进程中不能有其他线程在运行。
+    /// 需要同时运行多个沙盒时，应在同一线程中依次创建，再分别调用 wait
+    pub(crate) fn spawn(&mut self) -> Result<Running> {
+        // 沙盒内部挂载失败只能以 SystemError 结束，因此提前检查
+        if self.rootfs.is_empty() {
+            return Err(Error::MountError(String::from("rootfs must be specified")));
+        }
+        for bind in &self.binds {
+            bind.validate()?;
+            self.validate_mount_point(&bind.target, std::path::Path::new(&bind.source).is_dir())?;
+        }
+        for tmpfs in &self.tmpfs {
+            tmpfs.validate()?;
+            self.validate_mount_point(&tmpfs.target, true)?;
+        }
+        if self.workdir_size.is_none() && !(self.copy_in.is_empty() && self.copy_out.is_empty()) {
+            return Err(Error::MountError(String::from("copying files requires a tmpfs workdir")));
+        }
+        for file in self.copy_in.iter().chain(&self.copy_out) {
+            file.validate()?;
+        }
+        for file in &self.copy_in {
+            if !std::path::Path::new(&file.host).is_file() {
+                return Err(Error::MountError(format!("{} is not a regular file", file.host)));
+            }
+        }
+        // 临时目录在运行结束、离开作用域时删除
+        let overlay_temp = if self.overlay {
+            Some(try_io!(tempfile::Builder::new().prefix("newbie-overlay-").tempdir()))
+        } else {
+            None
+        };
+        self.overlay_layers = overlay_temp.as_ref().map(|temp| temp.path().to_string_lossy().to_string());
+        if let Some(layers) = &self.overlay_layers {
+            mount::prepare_overlay(&self.rootfs, layers, self.overlay_dir.as_deref())?;
+        }
+        // tmpfs 只挂载在沙盒的挂载命名空间中，外部只会留下一个空目录
+        let workdir_temp = match self.workdir_size {
+            Some(_) => Some(try_io!(tempfile::Builder::new().prefix("newbie-workdir-").tempdir())),
+            None => None,
+        };
+        self.workdir_mount = workdir_temp.as_ref().map(|temp| temp.path().to_string_lossy().to_string());
+        // 额外的 3 个分别为沙盒内部的 1、1 的计时线程与 3 自身
         let pids = if self.pids > 0 { self.pids + 3 } else { 0 };
         let options = CGroupOptions {
             version: self.cgroup,
+            parent: self.cgroup_parent.clone(),
             pids,
+            memory: self.memory_limit.map_or(0, |l| i64::from(l) * 1024),
+            cpu_quota: self.cpu_quota.map_or(0, |l| (l * CPU_PERIOD as f64) as i64),
+            cpuset_cpus: self.cpuset_cpus.clone(),
+            cpuset_mems: self.cpuset_mems.clone(),
+            io: self.io_limit(),
         };
-        let cgroup = CGroup::apply(options).unwrap();
+        let cgroup = CGroup::apply(options)?;
+        // 记录自动检测得到的版本，用于输出沙盒信息
+        self.cgroup = cgroup.version();
+        self.control_group = Some(cgroup);
+        // 沙盒内部通过此管道将程序的运行结果回传
+        let mut report_fds = [0; 2];
+        unsafe {
+            syscall_or_panic!(libc::pipe2(report_fds.as_mut_ptr(), libc::O_CLOEXEC));
+        }
+        self.report_fd = report_fds[1];
+        // 需要限制输出大小或捕获输出时，程序的输出经由管道转发，由外部进程在超出限制时截断
+        let stdout_target = if self.capture_stdout { None } else { Some(self.stdout_fd) };
+        let stderr_target = if self.capture_stderr { None } else { Some(self.stderr_fd) };
+        let stdout_pipe = if self.stdout_limit.is_some() || self.capture_stdout {
+            Some(output::pipe()?)
+        } else {
+            None
+        };
+        let stderr_pipe = if self.stderr_limit.is_some() || self.capture_stderr {
+            Some(output::pipe()?)
+        } else {
+            None
+        };
+        let stdin_pipe = if self.stdin_data.is_some() {
+            Some(output::pipe()?)
+        } else {
+            None
+        };
+        let (original_stdin, original_stdout, original_stderr) = (self.stdin_fd, self.stdout_fd, self.stderr_fd);
+        if let Some((read, _)) = stdin_pipe {
+            self.stdin_fd = read;
+        }
+        if let Some((_, write)) = stdout_pipe {
+            self.stdout_fd = write;
+        }
+        if let Some((_, write)) = stderr_pipe {
+            self.stderr_fd = write;
+        }
         let stack = unsafe {
             libc::mmap(
                 ptr::null_mut(),
@@ -172,17 +758,363 @@ impl Sandbox {
                     | libc::CLONE_NEWNET  // 设置新的网络空间，如果没有配置网络，则该沙盒内部将无法联网
                     | libc::CLONE_NEWNS  // 为沙盒内部设置新的 namespaces 空间
                     | libc::CLONE_NEWIPC  // IPC 隔离
-                    | libc::CLONE_NEWCGROUP  // 在新的 CGROUP 中创建沙盒
                     | libc::CLONE_NEWPID, // 外部进程对沙盒不可见
+                // 沙盒内部会先加入控制组，再自行创建新的 cgroup namespace，因此此处不使用 CLONE_NEWCGROUP
                 self as *mut _ as *mut libc::c_void,
             ))
         };
         debug!("run sandbox pid = {}", pid);
-        let status = wait_it(pid);
+        unsafe {
+            libc::close(report_fds[1]);
+        }
+        // 沙盒内部已经持有管道的写入端，恢复原本的输出目标
+        self.stdin_fd = original_stdin;
+        self.stdout_fd = original_stdout;
+        self.stderr_fd = original_stderr;
+        let stdin_pipe = stdin_pipe.map(|(read, write)| {
+            unsafe {
+                libc::close(read);
+            }
+            write
+        });
+        // 转发的目标在转发结束后才能关闭，其余交出的描述符由调用方关闭
+        let relay_targets = [stdout_pipe.and(stdout_target), stderr_pipe.and(stderr_target)];
+        let (relayed_fds, handover_fds): (Vec<i32>, Vec<i32>) = self
+            .handover_fds
+            .drain(..)
+            .partition(|fd| relay_targets.contains(&Some(*fd)));
+        self.handover_fds = handover_fds;
+        Ok(Running {
+            pid,
+            stack: stack as usize,
+            report_fd: report_fds[0],
+            stdin_pipe,
+            stdout_pipe: read_end(stdout_pipe),
+            stderr_pipe: read_end(stderr_pipe),
+            stdout_target,
+            stderr_target,
+            relayed_fds,
+            overlay_temp,
+            workdir_temp,
+        })
+    }
+
+    /// 启动转发线程，等待 spawn 创建的沙盒结束并得出运行结果
+    pub(crate) fn wait(&mut self, running: Running) -> Result<RunnerStatus> {
+        let Running {
+            pid,
+            stack,
+            report_fd,
+            stdin_pipe,
+            stdout_pipe,
+            stderr_pipe,
+            stdout_target,
+            stderr_target,
+            relayed_fds,
+            overlay_temp,
+            workdir_temp,
+        } = running;
+        let feeder = stdin_pipe.map(|write| Feeder::spawn(write, self.stdin_data.clone().unwrap_or_default()));
+        let procs = self.control_group.as_ref().and_then(CGroup::procs).unwrap_or_default();
+        let stopper = Stopper::new(procs, pid);
+        let stdout_relay = relay(stdout_pipe, stdout_target, self.stdout_limit, &stopper);
+        let stderr_relay = relay(stderr_pipe, stderr_target, self.stderr_limit, &stopper);
+        let workdir = if self.workdir.is_empty() { String::from(".") } else { self.workdir.clone() };
+        // tmpfs 工作目录的大小已由 workdir_size 限制，外部的工作目录不会被使用，无需检查
+        let watcher = match self.workdir_size {
+            Some(_) => None,
+            None => self
+                .workdir_limit
+                .map(|limit| WorkdirWatcher::spawn(workdir.clone(), limit, stopper.clone())),
+        };
+        // 沙盒在回传结果之前回传复制出的文件，需要在其退出前读取，否则会阻塞在写满的管道上
+        let received = self.copy_out.is_empty() || runit::receive_outputs(report_fd, &self.copy_out, self.copy_out_bytes());
+        let report = if received { runit::read_report(report_fd) } else { None };
+        // 回传中断时沙盒可能仍在写入，关闭管道使其写入失败并退出
+        unsafe {
+            libc::close(report_fd);
+        }
+        // 此处获取的数值为沙盒的总资源用量，仅在沙盒未能回传结果时使用
+        let sandbox_status = wait_it(pid);
+        // 沙盒内的进程全部退出后管道的另一端随之关闭，转发线程会自行结束
+        if let Some(feeder) = feeder {
+            feeder.join();
+        }
+        let stdout_relay = stdout_relay.map(Relay::join).unwrap_or_default();
+        let stderr_relay = stderr_relay.map(Relay::join).unwrap_or_default();
+        for fd in relayed_fds {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+        // 检查间隔内写入的内容由运行结束后的检查兜底
+        let workdir_exceeded = match (watcher, self.workdir_limit) {
+            (Some(watcher), Some(limit)) => {
+                watcher.join() || output::dir_usage(std::path::Path::new(&workdir)) > limit
+            }
+            _ => false,
+        };
+        let output_exceeded = stdout_relay.exceeded || stderr_relay.exceeded || workdir_exceeded;
+        let cgroup = self.control_group.take().unwrap();
+        // 控制组统计的 CPU 时间包含整个进程树，扣除沙盒内部 1 与 2 的开销后作为最终结果，单位 us
+        let cpu_usage = report.as_ref().and_then(|report| {
+            cgroup.cpu_usage().map(|usage| (usage - report.overhead).max(0))
+        });
+        let mut status = match &report {
+            Some(report) => {
+                let mut status = RunnerStatus::new(report.status, &report.rusage);
+                status.real_time_used = report.real_time_used;
+                if let Some(cpu_usage) = cpu_usage {
+                    status.time_used = cpu_usage / 1000;
+                }
+                // 控制组统计的峰值包含沙盒内的所有进程，比单个进程的 ru_maxrss 更可靠
+                if let Some(peak) = cgroup.memory_peak() {
+                    status.memory_used = peak / 1024;
+                }
+                if let Some((read, write)) = cgroup.io_bytes() {
+                    status.io_read_bytes = read;
+                    status.io_write_bytes = write;
+                }
+                status
+            }
+            None => sandbox_status,
+        };
+        status.verdict = self.verdict(report.as_ref(), &status, cpu_usage, &cgroup.events(), output_exceeded);
+        if self.capture_stdout {
+            status.stdout = Some(stdout_relay.data);
+        }
+        if self.capture_stderr {
+            status.stderr = Some(stderr_relay.data);
+        }
         unsafe {
             syscall_or_panic!(libc::munmap(stack as *mut libc::c_void, STACK_SIZE));
-            drop(cgroup);
         }
-        status
+        cgroup.destroy()?;
+        self.overlay_layers = None;
+        self.workdir_mount = None;
+        drop(overlay_temp);
+        drop(workdir_temp);
+        Ok(status)
+    }
+
+    /// 根据资源限制、退出信号与控制组事件判定运行结果
+    ///
+    /// cpu_usage 为控制组统计的 CPU 时间，单位 us，不可用时使用 rusage 中的数值
+    ///
+    /// output_exceeded 表示输出或工作目录超出了限制，此时程序已被外部进程结束，其退出状态不再有意义
+    fn verdict(
+        &self,
+        report: Option<&Report>,
+        status: &RunnerStatus,
+        cpu_usage: Option<i64>,
+        events: &CGroupEvents,
+        output_exceeded: bool,
+    ) -> Verdict {
+        let report = match report {
+            Some(report) => report,
+            None => return Verdict::SystemError,
+        };
+        // seccomp 拦截危险的系统调用时会以 SIGSYS 结束进程
+        if status.signal == libc::SIGSYS {
+            return Verdict::SecurityViolation;
+        }
+        if output_exceeded {
+            return Verdict::OutputLimitExceeded;
+        }
+        // 使用 us 精度比较，超出限制 1ms 以内也会被判定为超时
+        let time_exceeded = match self.time_limit {
+            Some(time_limit) => {
+                let cpu_usage = cpu_usage.unwrap_or(status.rusage.utime + status.rusage.stime);
+                cpu_usage > i64::from(time_limit) * 1000
+            }
+            None => false,
+        } || match self.real_time_limit {
+            Some(real_time_limit) => status.real_time_used > i64::from(real_time_limit),
+            None => false,
+        };
+        if report.timeout || status.signal == libc::SIGXCPU || time_exceeded {
+            return Verdict::TimeLimitExceeded;
+        }
+        if events.oom_kill > 0 {
+            return Verdict::MemoryLimitExceeded;
+        }
+        if let Some(memory_limit) = self.memory_limit {
+            if status.memory_used > i64::from(memory_limit) {
+                return Verdict::MemoryLimitExceeded;
+            }
+        }
+        if status.signal == libc::SIGXFSZ {
+            return Verdict::OutputLimitExceeded;
+        }
+        if status.signal == 0 && status.exit_code == 0 {
+            return Verdict::Accepted;
+        }
+        // fork 失败后程序仍可能正常结束，此时不影响结果
+        if self.pids > 0 && events.pids_max > 0 {
+            return Verdict::ProcessLimitExceeded;
+        }
+        Verdict::RuntimeError
+    }
+}
+
+/// 打开运行结果的输出目标，`/STDOUT/` 表示标准输出，其余为文件路径
+///
+/// 批量运行等不经由单个沙盒输出结果的场景使用，返回的描述符由调用方管理
+pub fn open_result(s: &str) -> Result<i32> {
+    if s == "/STDOUT/" {
+        return Ok(1);
+    }
+    let file = try_io!(std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).mode(0o644).open(s));
+    Ok(file.into_raw_fd())
+}
+
+/// 开始将程序的输出从管道的读取端转发到 target，target 为 None 时捕获到内存中
+fn relay(read: Option<i32>, target: Option<i32>, limit: Option<u64>, stopper: &Stopper) -> Option<Relay> {
+    Some(Relay::spawn(read?, target, limit.unwrap_or(u64::MAX), stopper.clone()))
+}
+
+/// 关闭外部进程持有的管道写入端，返回读取端
+fn read_end(pipe: Option<(i32, i32)>) -> Option<i32> {
+    let (read, write) = pipe?;
+    unsafe {
+        libc::close(write);
+    }
+    Some(read)
+}
+
+/// 已创建但尚未等待的沙盒
+pub(crate) struct Running {
+    pid: i32,
+    /// 沙盒内部使用的栈的地址，以整数保存以便在线程间传递
+    stack: usize,
+    report_fd: i32,
+    /// 标准输入管道的写入端
+    stdin_pipe: Option<i32>,
+    /// 输出管道的读取端
+    stdout_pipe: Option<i32>,
+    stderr_pipe: Option<i32>,
+    stdout_target: Option<i32>,
+    stderr_target: Option<i32>,
+    /// 作为转发目标交出的描述符，转发结束后关闭
+    relayed_fds: Vec<i32>,
+    overlay_temp: Option<tempfile::TempDir>,
+    workdir_temp: Option<tempfile::TempDir>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 判定结果所需的运行情况，时间单位为 ms，CPU 时间单位为 us，内存单位为 kib
+    struct Run {
+        status: i32,
+        timeout: bool,
+        cpu_usage: i64,
+        real_time_used: i64,
+        memory_used: i64,
+        pids_max: u64,
+        oom_kill: u64,
+        output_exceeded: bool,
+    }
+
+    const EXITED: Run = Run {
+        status: 0,
+        timeout: false,
+        cpu_usage: 0,
+        real_time_used: 0,
+        memory_used: 0,
+        pids_max: 0,
+        oom_kill: 0,
+        output_exceeded: false,
+    };
+
+    fn exit_code(code: i32) -> i32 {
+        code << 8
+    }
+
+    fn verdict(sandbox: &Sandbox, run: &Run) -> Verdict {
+        let rusage = utils::new_rusage();
+        let report = Report {
+            status: run.status,
+            rusage,
+            timeout: run.timeout,
+            real_time_used: run.real_time_used,
+            overhead: 0,
+        };
+        let mut status = RunnerStatus::new(run.status, &rusage);
+        status.real_time_used = run.real_time_used;
+        status.memory_used = run.memory_used;
+        let events = CGroupEvents {
+            pids_max: run.pids_max,
+            oom_kill: run.oom_kill,
+        };
+        sandbox.verdict(Some(&report), &status, Some(run.cpu_usage), &events, run.output_exceeded)
+    }
+
+    #[test]
+    fn verdicts() {
+        let sandbox = Sandbox::new(vec![]).unwrap().time_limit(1000).real_time_limit(3000).memory_limit(65536).pids(8);
+        let cases = [
+            (EXITED, Verdict::Accepted),
+            (Run { status: exit_code(1), ..EXITED }, Verdict::RuntimeError),
+            (Run { status: libc::SIGSEGV, ..EXITED }, Verdict::RuntimeError),
+            (Run { status: libc::SIGSYS, ..EXITED }, Verdict::SecurityViolation),
+            (Run { status: libc::SIGSYS, timeout: true, output_exceeded: true, ..EXITED }, Verdict::SecurityViolation),
+            // 超出限制 1ms 以内同样超时
+            (Run { cpu_usage: 1_000_000, ..EXITED }, Verdict::Accepted),
+            (Run { cpu_usage: 1_000_001, ..EXITED }, Verdict::TimeLimitExceeded),
+            (Run { real_time_used: 3001, ..EXITED }, Verdict::TimeLimitExceeded),
+            (Run { status: libc::SIGKILL, timeout: true, ..EXITED }, Verdict::TimeLimitExceeded),
+            (Run { status: libc::SIGXCPU, ..EXITED }, Verdict::TimeLimitExceeded),
+            (Run { status: libc::SIGKILL, timeout: true, oom_kill: 1, ..EXITED }, Verdict::TimeLimitExceeded),
+            (Run { status: libc::SIGKILL, oom_kill: 1, ..EXITED }, Verdict::MemoryLimitExceeded),
+            (Run { memory_used: 65537, ..EXITED }, Verdict::MemoryLimitExceeded),
+            (Run { status: libc::SIGXFSZ, ..EXITED }, Verdict::OutputLimitExceeded),
+            (Run { status: libc::SIGKILL, output_exceeded: true, ..EXITED }, Verdict::OutputLimitExceeded),
+            (Run { output_exceeded: true, cpu_usage: 2_000_000, ..EXITED }, Verdict::OutputLimitExceeded),
+            (Run { pids_max: 3, ..EXITED }, Verdict::Accepted),
+            (Run { status: exit_code(1), pids_max: 3, ..EXITED }, Verdict::ProcessLimitExceeded),
+            (Run { status: libc::SIGSEGV, pids_max: 3, ..EXITED }, Verdict::ProcessLimitExceeded),
+            (Run { status: libc::SIGKILL, pids_max: 3, oom_kill: 1, ..EXITED }, Verdict::MemoryLimitExceeded),
+        ];
+        for (i, (run, expected)) in cases.iter().enumerate() {
+            assert_eq!(verdict(&sandbox, run), *expected, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn verdict_without_limits() {
+        let sandbox = Sandbox::new(vec![]).unwrap();
+        let run = Run {
+            cpu_usage: 10_000_000,
+            real_time_used: 10_000,
+            memory_used: 1 << 20,
+            ..EXITED
+        };
+        assert_eq!(verdict(&sandbox, &run), Verdict::Accepted);
+        // 未限制进程数量时 pids.max 的事件来自外部的限制
+        assert_eq!(verdict(&sandbox, &Run { status: exit_code(1), pids_max: 1, ..EXITED }), Verdict::RuntimeError);
+        let status = RunnerStatus::new(0, &utils::new_rusage());
+        assert_eq!(sandbox.verdict(None, &status, None, &CGroupEvents::default(), false), Verdict::SystemError);
+    }
+
+    #[test]
+    fn new_and_result() {
+        let sandbox = Sandbox::new(vec![String::from("/usr/bin/echo")]).unwrap();
+        assert_eq!(sandbox.workdir, ".");
+        assert_eq!(sandbox.cgroup, 0);
+        assert_eq!(sandbox.result_fd, -1);
+        assert!(matches!(Sandbox::new(vec![String::from("a\0b")]), Err(Error::StringToCStringError(_))));
+        let result = sandbox.result(String::from("/nonexistent/result"));
+        assert!(matches!(result, Err(Error::IOError(_))));
+    }
+
+    #[test]
+    fn copy_out_limit() {
+        let sandbox = |size, limit| Sandbox::new(vec![]).unwrap().workdir_size(size).copy_out_limit(limit).copy_out_bytes();
+        assert_eq!(sandbox(1 << 20, 0), 1 << 20);
+        assert_eq!(sandbox(1 << 20, 1024), 1024);
+        assert_eq!(sandbox(1 << 20, 1 << 30), 1 << 20);
+        assert_eq!(sandbox(0, 1024), 0);
     }
 }
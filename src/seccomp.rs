@@ -42,9 +42,9 @@
 //! the `Trap` action: cause the kernel to send `SIGSYS` (signal number 31) to the process.
 //! Without a signal handler in place, the process will die with exit code 159 (128 + `SIGSYS`).
 //!
-//! ```should_panic
+//! ```no_run
 //! use std::convert::TryInto;
-//! use seccomp::*;
+//! use newbie_sandbox::seccomp::*;
 //!
 //! let buf = "Hello, world!";
 //! let filter = SeccompFilter::new(
@@ -109,8 +109,8 @@
 //! cause the kernel to send `SIGSYS` (signal number 31) to the process.
 //! A signal handler will catch `SIGSYS` and exit with code 159 on any other syscall.
 //!
-//! ```should_panic
-//! use seccomp::*;
+//! ```no_run
+//! use newbie_sandbox::seccomp::*;
 //! use std::convert::TryInto;
 //! use std::mem;
 //! use std::process::exit;
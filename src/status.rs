@@ -1,19 +1,247 @@
 use std::{
     fs::File,
     io::Write,
+    mem::ManuallyDrop,
     os::unix::io::FromRawFd,
 };
 use std::fmt;
+use std::str::FromStr;
 
-use crate::error::Result;
+use serde::Serialize;
 
-#[derive(Debug)]
+use crate::checker::CheckResult;
+use crate::error::{Error, Result};
+
+/// 结构化结果文档的格式版本，字段发生不兼容变动时递增
+pub const RESULT_VERSION: u32 = 1;
+
+/// 运行结果的输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultFormat {
+    /// `key = value` 形式的纯文本，兼容旧版输出
+    Text,
+    Json,
+    Toml,
+}
+
+impl FromStr for ResultFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ResultFormat::Text),
+            "json" => Ok(ResultFormat::Json),
+            "toml" => Ok(ResultFormat::Toml),
+            _ => Err(format!("unknown result format `{}`, expected json, text or toml", s)),
+        }
+    }
+}
+
+/// 评测结果
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Verdict {
+    Accepted,
+    /// 输出与答案不符，或交互题中交互程序判定答案错误
+    WrongAnswer,
+    /// 输出仅在空白字符上与答案不同
+    PresentationError,
+    /// 特殊评测程序给出了部分分
+    PartiallyCorrect,
+    /// 源代码编译失败，程序没有运行
+    CompileError,
+    TimeLimitExceeded,
+    MemoryLimitExceeded,
+    OutputLimitExceeded,
+    RuntimeError,
+    /// 沙盒自身出现异常，无法得到程序的运行结果
+    SystemError,
+    /// 程序调用了被禁止的系统调用
+    SecurityViolation,
+    /// 程序因超出允许的进程数量而未能正常结束
+    ProcessLimitExceeded,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// `libc::rusage` 的完整拷贝，时间单位为 us，其余字段含义同 getrusage(2)
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Rusage {
+    pub utime: i64,
+    pub stime: i64,
+    pub maxrss: i64,
+    pub ixrss: i64,
+    pub idrss: i64,
+    pub isrss: i64,
+    pub minflt: i64,
+    pub majflt: i64,
+    pub nswap: i64,
+    pub inblock: i64,
+    pub oublock: i64,
+    pub msgsnd: i64,
+    pub msgrcv: i64,
+    pub nsignals: i64,
+    pub nvcsw: i64,
+    pub nivcsw: i64,
+}
+
+impl From<&libc::rusage> for Rusage {
+    fn from(rusage: &libc::rusage) -> Self {
+        Rusage {
+            utime: rusage.ru_utime.tv_sec * 1_000_000 + rusage.ru_utime.tv_usec,
+            stime: rusage.ru_stime.tv_sec * 1_000_000 + rusage.ru_stime.tv_usec,
+            maxrss: rusage.ru_maxrss,
+            ixrss: rusage.ru_ixrss,
+            idrss: rusage.ru_idrss,
+            isrss: rusage.ru_isrss,
+            minflt: rusage.ru_minflt,
+            majflt: rusage.ru_majflt,
+            nswap: rusage.ru_nswap,
+            inblock: rusage.ru_inblock,
+            oublock: rusage.ru_oublock,
+            msgsnd: rusage.ru_msgsnd,
+            msgrcv: rusage.ru_msgrcv,
+            nsignals: rusage.ru_nsignals,
+            nvcsw: rusage.ru_nvcsw,
+            nivcsw: rusage.ru_nivcsw,
+        }
+    }
+}
+
+/// 本次运行实际应用的资源限制，未限制的项不会输出
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Limits {
+    /// 单位 ms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_limit: Option<i32>,
+    /// 单位 ms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub real_time_limit: Option<i32>,
+    /// 单位 kib
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit: Option<i32>,
+    /// 单位 byte
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_size_limit: Option<i32>,
+    /// 单位 byte
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout_limit: Option<u64>,
+    /// 单位 byte
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr_limit: Option<u64>,
+    /// 单位 byte
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workdir_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pids: Option<i32>,
+    /// 单位为核数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpuset_cpus: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpuset_mems: Option<String>,
+    /// 单位 byte/s
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_read_bps: Option<u64>,
+    /// 单位 byte/s
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_write_bps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_read_iops: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_write_iops: Option<u64>,
+}
+
+/// 沙盒自身的信息
+#[derive(Debug, Clone, Serialize)]
+pub struct Metadata {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub command: Vec<String>,
+    pub rootfs: String,
+    pub workdir: String,
+    pub cgroup: i32,
+    /// 沙盒从创建到退出的总耗时（包含初始化），单位 ms
+    pub wall_time: u64,
+}
+
+/// 结构化输出的完整文档
+///
+/// toml 要求所有普通值位于子表之前，因此 rusage、limits 与 sandbox 需要放在最后
+#[derive(Serialize)]
+struct ResultDocument<'a> {
+    version: u32,
+    #[serde(flatten)]
+    status: &'a RunnerStatus,
+    limits: &'a Limits,
+    sandbox: &'a Metadata,
+}
+
+#[derive(Debug, Serialize)]
 pub struct RunnerStatus {
+    /// CPU 时间，即用户态与内核态时间之和，单位 ms
+    ///
+    /// 控制组可用时为整个进程树的统计，否则与 process_time_used 相同
     pub time_used: i64,
+    /// 由 wait4 得到的 3 自身（含已回收子进程）的 CPU 时间，单位 ms
+    pub process_time_used: i64,
+    pub user_time_used: i64,
+    pub system_time_used: i64,
+    /// 真实时间，单位 ms
+    pub real_time_used: i64,
     pub memory_used: i64,
+    /// 控制组内所有进程从块设备读取与写入的总字节数，控制组不可用时为 0
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
     pub exit_code: i32,
     pub status: i32,
     pub signal: i32,
+    pub verdict: Verdict,
+    pub rusage: Rusage,
+    /// 捕获到内存中的标准输出与标准错误，未开启捕获时为 None，不会写入结果文件
+    #[serde(skip)]
+    pub stdout: Option<Vec<u8>>,
+    #[serde(skip)]
+    pub stderr: Option<Vec<u8>>,
+    /// 与标准答案比较的结果，未指定答案或程序未正常结束时为 None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<CheckResult>,
+    /// 交互模式中交互程序的运行结果，verdict 为其自身的判定结果
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interactor: Option<Box<RunnerStatus>>,
+    /// 先编译再运行时编译器的运行结果
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compile: Option<Box<CompileStatus>>,
+}
+
+/// 编译器的运行结果，verdict 为编译器自身的判定结果，非 Accepted 即为编译失败
+#[derive(Debug, Serialize)]
+pub struct CompileStatus {
+    /// 编译器的标准输出与标准错误
+    pub message: String,
+    #[serde(flatten)]
+    pub status: RunnerStatus,
+}
+
+impl CompileStatus {
+    pub(crate) fn result_to_text(&self, f: &mut File) -> Result<()> {
+        try_io!(write!(f,
+"compile_time_used = {}
+compile_real_time_used = {}
+compile_memory_used = {}
+compile_exit_code = {}
+compile_signal = {}
+compile_verdict = {}
+compile_message = {}
+", self.status.time_used, self.status.real_time_used, self.status.memory_used, self.status.exit_code,
+            self.status.signal, self.status.verdict, self.message.lines().collect::<Vec<_>>().join(" ")
+        ));
+        Ok(())
+    }
 }
 
 impl fmt::Display for RunnerStatus {
@@ -27,16 +255,212 @@ impl fmt::Display for RunnerStatus {
 }
 
 impl RunnerStatus {
-    pub fn result_to_fd(&self, fd: i32) -> Result<()> {
-        let mut f = unsafe { File::from_raw_fd(fd) };
-        try_io!(write!(&mut f,
+    /// 根据 wait4 得到的 status 与 rusage 生成运行结果，此时尚未判定 verdict
+    pub fn new(status: i32, rusage: &libc::rusage) -> Self {
+        let user_time_used = rusage.ru_utime.tv_sec * 1000 + rusage.ru_utime.tv_usec / 1000;
+        let system_time_used = rusage.ru_stime.tv_sec * 1000 + rusage.ru_stime.tv_usec / 1000;
+        let time_used = user_time_used + system_time_used;
+        let memory_used = rusage.ru_maxrss;
+        let mut exit_code = 0;
+        let exited = libc::WIFEXITED(status);
+        if exited {
+            exit_code = libc::WEXITSTATUS(status);
+        }
+        let signal = if libc::WIFSIGNALED(status) {
+            libc::WTERMSIG(status)
+        } else if libc::WIFSTOPPED(status) {
+            libc::WSTOPSIG(status)
+        } else {
+            0
+        };
+
+        RunnerStatus {
+            time_used,
+            process_time_used: time_used,
+            user_time_used,
+            system_time_used,
+            real_time_used: 0,
+            memory_used,
+            io_read_bytes: 0,
+            io_write_bytes: 0,
+            exit_code,
+            signal,
+            status,
+            verdict: Verdict::SystemError,
+            rusage: Rusage::from(rusage),
+            stdout: None,
+            stderr: None,
+            check: None,
+            interactor: None,
+            compile: None,
+        }
+    }
+
+    pub fn result_to_fd(&self, fd: i32, format: ResultFormat, limits: &Limits, metadata: &Metadata) -> Result<()> {
+        // 描述符由调用方管理，此处不能在 drop 时将其关闭
+        let mut f = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let document = ResultDocument {
+            version: RESULT_VERSION,
+            status: self,
+            limits,
+            sandbox: metadata,
+        };
+        match format {
+            ResultFormat::Text => self.result_to_text(&mut f),
+            ResultFormat::Json => {
+                let json = match serde_json::to_string_pretty(&document) {
+                    Ok(json) => json,
+                    Err(e) => return Err(Error::SerializeError(e.to_string())),
+                };
+                try_io!(writeln!(&mut *f, "{}", json));
+                Ok(())
+            }
+            ResultFormat::Toml => {
+                let toml = match toml::to_string(&document) {
+                    Ok(toml) => toml,
+                    Err(e) => return Err(Error::SerializeError(e.to_string())),
+                };
+                try_io!(write!(&mut *f, "{}", toml));
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn result_to_text(&self, f: &mut File) -> Result<()> {
+        try_io!(write!(f,
 "time_used = {}
+process_time_used = {}
+user_time_used = {}
+system_time_used = {}
+real_time_used = {}
 memory_used = {}
+io_read_bytes = {}
+io_write_bytes = {}
 exit_code = {}
 status = {}
 signal = {}
-", self.time_used, self.memory_used, self.exit_code, self.status, self.signal
+verdict = {}
+", self.time_used, self.process_time_used, self.user_time_used, self.system_time_used, self.real_time_used,
+            self.memory_used, self.io_read_bytes, self.io_write_bytes, self.exit_code, self.status, self.signal, self.verdict
         ));
+        if let Some(check) = &self.check {
+            // 纯文本中每个结果占一行，多行的信息合并为一行
+            try_io!(writeln!(f, "check_message = {}", check.message.lines().collect::<Vec<_>>().join(" ")));
+            if let Some(score) = check.score {
+                try_io!(writeln!(f, "check_score = {}", score));
+            }
+            if let Some(position) = check.position {
+                try_io!(writeln!(f, "check_position = {}", position));
+            }
+        }
+        if let Some(interactor) = &self.interactor {
+            try_io!(write!(f,
+"interactor_time_used = {}
+interactor_real_time_used = {}
+interactor_memory_used = {}
+interactor_exit_code = {}
+interactor_signal = {}
+interactor_verdict = {}
+", interactor.time_used, interactor.real_time_used, interactor.memory_used, interactor.exit_code,
+                interactor.signal, interactor.verdict
+            ));
+        }
+        if let Some(compile) = &self.compile {
+            compile.result_to_text(f)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+
+    /// 运行结果的字段名，结构化输出中为顶层的键
+    const FIELDS: [&str; 16] = [
+        "version",
+        "time_used",
+        "process_time_used",
+        "user_time_used",
+        "system_time_used",
+        "real_time_used",
+        "memory_used",
+        "io_read_bytes",
+        "io_write_bytes",
+        "exit_code",
+        "status",
+        "signal",
+        "verdict",
+        "rusage",
+        "limits",
+        "sandbox",
+    ];
+
+    fn output(format: ResultFormat) -> String {
+        let mut status = RunnerStatus::new(1 << 8, &crate::utils::new_rusage());
+        status.verdict = Verdict::RuntimeError;
+        let limits = Limits {
+            time_limit: Some(1000),
+            memory_limit: Some(65536),
+            ..Limits::default()
+        };
+        let metadata = Metadata {
+            name: "newbie-sandbox",
+            version: "1.0",
+            command: vec!["/tmp/main".to_string()],
+            rootfs: "/rootfs".to_string(),
+            workdir: "/tmp".to_string(),
+            cgroup: 1,
+            wall_time: 10,
+        };
+        let mut file = tempfile::tempfile().unwrap();
+        status.result_to_fd(file.as_raw_fd(), format, &limits, &metadata).unwrap();
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        content
+    }
+
+    fn keys<'a>(keys: impl Iterator<Item = &'a String>) -> BTreeSet<&'a str> {
+        keys.map(|key| key.as_str()).collect()
+    }
+
+    #[test]
+    fn json_document() {
+        let document: serde_json::Value = serde_json::from_str(&output(ResultFormat::Json)).unwrap();
+        let document = document.as_object().unwrap();
+        assert_eq!(keys(document.keys()), FIELDS.iter().copied().collect());
+        assert_eq!(document["version"], u64::from(RESULT_VERSION));
+        assert_eq!(document["exit_code"], 1);
+        assert_eq!(document["verdict"], "RuntimeError");
+        // 未限制的项不会输出
+        let limits = document["limits"].as_object().unwrap();
+        assert_eq!(keys(limits.keys()), ["memory_limit", "time_limit"].iter().copied().collect());
+        let sandbox = document["sandbox"].as_object().unwrap();
+        let expected = ["name", "version", "command", "rootfs", "workdir", "cgroup", "wall_time"];
+        assert_eq!(keys(sandbox.keys()), expected.iter().copied().collect());
+    }
+
+    #[test]
+    fn toml_document() {
+        let document: toml::Value = toml::from_str(&output(ResultFormat::Toml)).unwrap();
+        let document = document.as_table().unwrap();
+        assert_eq!(keys(document.keys()), FIELDS.iter().copied().collect());
+        assert_eq!(document["version"].as_integer(), Some(i64::from(RESULT_VERSION)));
+        assert_eq!(document["verdict"].as_str(), Some("RuntimeError"));
+        assert_eq!(document["limits"]["time_limit"].as_integer(), Some(1000));
+        assert_eq!(document["sandbox"]["command"][0].as_str(), Some("/tmp/main"));
+    }
+
+    #[test]
+    fn text_lines() {
+        let text = output(ResultFormat::Text);
+        let keys: Vec<&str> = text.lines().map(|line| line.split(" = ").next().unwrap()).collect();
+        // 纯文本输出兼容旧版，不包含版本、资源限制与沙盒信息
+        assert_eq!(keys, FIELDS[1..13].to_vec());
+        assert!(text.ends_with("exit_code = 1\nstatus = 256\nsignal = 0\nverdict = RuntimeError\n"));
+    }
+}
@@ -70,4 +70,40 @@ pub fn new_rusage() -> libc::rusage {
         ru_nvcsw: 0 as libc::c_long,
         ru_nivcsw: 0 as libc::c_long,
     }
-}
\ No newline at end of file
+}
+
+/// 获取路径所在的块设备号 `(major, minor)`，分区会被换算为其所属的整块磁盘
+///
+/// cgroup 的 I/O 限制只能作用于整块磁盘，路径不在块设备上（如 tmpfs、overlay）时返回 None
+pub fn block_device(path: &str) -> Option<(u32, u32)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let dev = std::os::unix::fs::MetadataExt::dev(&metadata);
+    let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+    let sys_path = std::path::PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+    if !sys_path.exists() {
+        return None;
+    }
+    if !sys_path.join("partition").exists() {
+        return Some((major, minor));
+    }
+    let disk = std::fs::read_to_string(sys_path.join("../dev")).ok()?;
+    let (major, minor) = disk.trim().split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// 关闭当前进程中除 keep 以外的所有描述符
+pub fn close_fds(keep: &[i32]) {
+    let fds: Vec<i32> = match std::fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|fd| fd.parse().ok()))
+            .collect(),
+        Err(_) => return,
+    };
+    // 读取目录时打开的描述符此时已经关闭，close 会返回 EBADF，无需处理
+    for fd in fds.into_iter().filter(|fd| !keep.contains(fd)) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}